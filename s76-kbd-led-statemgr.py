@@ -1,112 +1,4977 @@
 #!/usr/bin/env python3
 
 import argparse
+import contextlib
+import copy
+import datetime
+import difflib
+import errno
+import fcntl
+import fnmatch
+import getpass
+import glob
+import grp
+import hashlib
+import io
 import json
+import logging
+import os
 import pathlib
+import platform
+import pwd
 import re
+import select
+import shlex
+import shutil
+import signal
+import socket
+import stat
+import struct
+import subprocess
+import sys
+import tempfile
+import time
 
+try:
+    import tomllib
+except ImportError:
+    # Python < 3.11; the tomli package is the same parser
+    try:
+        import tomli as tomllib
+    except ImportError:
+        tomllib = None
 
-def read_configuration() -> dict:
-    configuration_paths = [
-        pathlib.PosixPath("/usr/local/etc/s76-kbd-led-statemgr.json"),
-        pathlib.PosixPath("/etc/s76-kbd-led-statemgr.json"),
-    ]
-    default_config = {
-        "brightness": {
-            "path": "/sys/class/leds/system76_acpi::kbd_backlight/brightness",
-            "default": "48",
-        },
-        "color": {
-            "path": "/sys/class/leds/system76_acpi::kbd_backlight/color",
-            "default": "FF0000",
-        },
-        "state_path": "/var/lib/s76-kbd-led-statemgr/state.json",
-    }
+try:
+    import yaml
+except ImportError:
+    # YAML is optional, for sites that template their configuration as YAML
+    yaml = None
+
+try:
+    import curses
+except ImportError:
+    # only 'tui' needs it, and some Python builds leave out _curses
+    curses = None
+
+__version__ = "0.2.0"
+
+logger = logging.getLogger("s76-kbd-led-statemgr")
+
+# TOML and YAML are preferred over JSON in the same directory, as they allow comments
+CONFIGURATION_PATHS = [
+    pathlib.PosixPath("/usr/local/etc/s76-kbd-led-statemgr.toml"),
+    pathlib.PosixPath("/usr/local/etc/s76-kbd-led-statemgr.yaml"),
+    pathlib.PosixPath("/usr/local/etc/s76-kbd-led-statemgr.json"),
+    pathlib.PosixPath("/etc/s76-kbd-led-statemgr.toml"),
+    pathlib.PosixPath("/etc/s76-kbd-led-statemgr.yaml"),
+    pathlib.PosixPath("/etc/s76-kbd-led-statemgr.json"),
+]
+# bump these, and append to the matching *_MIGRATIONS list, whenever a format changes incompatibly
+CONFIGURATION_VERSION = 1
+STATE_VERSION = 3
+DEFAULT_CONFIGURATION = {
+    "version": CONFIGURATION_VERSION,
+    "brightness": {
+        "path": "/sys/class/leds/system76_acpi::kbd_backlight/brightness",
+        "default": "48",
+    },
+    "color": {
+        "path": "/sys/class/leds/system76_acpi::kbd_backlight/color",
+        "default": "FF0000",
+    },
+    "state_path": "/var/lib/s76-kbd-led-statemgr/state.json",
+}
+# where unprivileged runs keep their state, expanded as configured paths are
+DEFAULT_USER_STATE_PATH = "${XDG_STATE_HOME}/s76-kbd-led-statemgr/state.json"
+# where 'pre' saves the state instead when the state directory can't be written, e.g. a read-only /var
+DEFAULT_RUNTIME_STATE_PATH = "/run/s76-kbd-led-statemgr/state.json"
+# what a relative state_path or runtime_state_path is taken from, without systemd's $STATE_DIRECTORY or
+# $RUNTIME_DIRECTORY
+RELATIVE_STATE_DIRECTORIES = {
+    "state_path": "/var/lib/s76-kbd-led-statemgr",
+    "runtime_state_path": "/run/s76-kbd-led-statemgr",
+}
+# the settings each table of the configuration may hold
+KNOWN_SETTINGS = {
+    None: [
+        "version", "devices", "brightness", "color", "zones", "profiles", "power", "schedule", "restore_delay_ms",
+        "reapply_delay_ms", "wait_for_device_ms", "retries", "retry_interval_ms", "state_path", "user_state_path",
+        "runtime_state_path", "state_max_age", "state_mode", "state_directory_mode", "state_owner", "state_group",
+        "history", "backups", "hooks", "pre", "post", "backend", "openrgb", "ite8291", "system76_power", "mock",
+        "match",
+    ],
+    "device": ["brightness", "color", "zones", "backend", "openrgb", "ite8291", "system76_power", "mock", "match"],
+    "match": ["driver", "parent", "name"],
+    "mock": ["path", "max_brightness"],
+    "system76_power": ["object"],
+    "openrgb": ["host", "port", "controller"],
+    "ite8291": ["path"],
+    "zone": ["path", "default"],
+    "profile": ["brightness", "color", "zones"],
+    "power": ["ac", "battery", "path"],
+    "hooks": ["before_save", "after_save", "before_restore", "after_restore"],
+    "pre": ["save_brightness", "save_color", "save_zones"],
+    "post": ["apply_brightness", "apply_color", "apply_zones"],
+    "schedule": ["profile", "brightness", "color"],
+    "brightness": ["path", "default", "step", "levels", "max"],
+    "color": ["enabled", "path", "default", "palette", "allowed", "enforce", "format"],
+}
+# what normalize_configuration adds to the tables: the path as configured, for a glob pattern or a list of
+# candidates, to resolve again when the device was missing at first
+DERIVED_SETTINGS = ["configured_path"]
+DROP_IN_PATH = pathlib.PosixPath("/etc/s76-kbd-led-statemgr.d")
+ENVIRONMENT_OVERRIDES = {
+    "S76_KBD_BRIGHTNESS_PATH": ("brightness", "path"),
+    "S76_KBD_BRIGHTNESS_DEFAULT": ("brightness", "default"),
+    "S76_KBD_BRIGHTNESS_STEP": ("brightness", "step"),
+    "S76_KBD_COLOR_PATH": ("color", "path"),
+    "S76_KBD_COLOR_DEFAULT": ("color", "default"),
+    "S76_KBD_STATE_PATH": (None, "state_path"),
+    "S76_KBD_BACKEND": (None, "backend"),
+}
+# for a short while after resume the LED class device can be busy, or briefly not there at all (ENOENT, until the
+# ACPI or EC driver registers it again)
+RETRY_ERRORS = (errno.EBUSY, errno.ENODEV, errno.ENXIO, errno.EAGAIN, errno.ENOENT)
+DEFAULT_RETRIES = 5
+DEFAULT_RETRY_INTERVAL_MS = 200
+# the interval doubles after each attempt, up to this, so the defaults give up after a little over five seconds
+MAX_RETRY_INTERVAL_MS = 2000
+# 'restore' waiting for the LED device (wait_for_device_ms) listens for the kernel's uevents, as udev does, and
+# looks again at least this often in case one is missed or it cannot listen
+NETLINK_KOBJECT_UEVENT = 15
+DEVICE_POLL_INTERVAL_MS = 250
+# how many replaced states 'save' and the rest keep, as state.history-1.json (the latest) onwards
+DEFAULT_HISTORY = 5
+# how long to wait for another run to finish with the state file
+LOCK_TIMEOUT = 10
+# seconds after a resume that 'track' leaves the keyboard to 'restore'
+TRACK_RESUME_GRACE = 10
+# the state locks this run holds, by lock file
+STATE_LOCKS = {}
+# state files whose history this run has already rotated, and those it has backed up
+ROTATED_HISTORY = set()
+BACKED_UP = set()
+# color attributes already warned about being missing, so a long-running command warns only once for each
+MISSING_COLORS = set()
+# what a state is made of, each of which the 'pre' and 'post' tables can leave alone
+STATE_PARTS = ("brightness", "color", "zones")
+# hooks run in the sleep path, so one that hangs must not hold up suspend or resume for long
+HOOK_TIMEOUT = 10
+LEDS_PATH = pathlib.PosixPath("/sys/class/leds")
+# where a device found by 'match' is taken to be while nothing matches, so that it reads and writes as missing
+UNMATCHED_PATH = LEDS_PATH / "(no match)"
+POWER_SUPPLY_PATH = pathlib.PosixPath("/sys/class/power_supply")
+# searched for system76-power's keyboard settings by 'migrate-from-system76-power' when not given a file
+SYSTEM76_POWER_PATHS = [pathlib.PosixPath("/etc/system76-power"), pathlib.PosixPath("/var/lib/system76-power")]
+DRIVER_MODULES = ["system76_acpi", "system76"]
+# how color attributes spell RRGGBB: as it is (System76), "0xrrggbb" (tuxedo_keyboard's platform device) or
+# "R G B" in decimal (a multicolor LED's multi_intensity)
+COLOR_FORMATS = ["hex", "0x", "rgb"]
+# OpenRGB's network SDK, for keyboards only it knows how to drive; protocol version 0 is spoken, which every server
+# accepts, and the requests are numbered as in its NetworkProtocol.h
+OPENRGB_DEFAULT_HOST = "127.0.0.1"
+OPENRGB_DEFAULT_PORT = 6742
+OPENRGB_TIMEOUT = 5
+OPENRGB_KEYBOARD_TYPE = 5
+OPENRGB_REQUEST_CONTROLLER_COUNT = 0
+OPENRGB_REQUEST_CONTROLLER_DATA = 1
+OPENRGB_SET_CLIENT_NAME = 50
+OPENRGB_UPDATE_LEDS = 1050
+OPENRGB_SET_CUSTOM_MODE = 1100
+# the ITE 8291 USB controller of per-key RGB keyboards on some System76 and Clevo models, which has no sysfs
+# interface; it is driven through hidraw as ite8291r3-ctl does, taking 8-byte feature reports for commands and
+# a row of colors at a time
+ITE8291_IDS = [(0x048D, 0x6004), (0x048D, 0x6006), (0x048D, 0xCE00)]
+ITE8291_MAX_BRIGHTNESS = 50
+ITE8291_ROWS = 6
+ITE8291_COLUMNS = 21
+HIDRAW_PATH = pathlib.PosixPath("/sys/class/hidraw")
+# where the mock backend keeps its pretend devices, unless 'mock.path' says otherwise
+DEFAULT_MOCK_PATH = os.path.join(tempfile.gettempdir(), f"s76-kbd-led-statemgr-mock-{os.getuid()}")
+# system76-power's keyboard objects on the system bus, reached with busctl so that the daemon stays the one owning
+# the LEDs rather than both writing to sysfs
+SYSTEM76_POWER_SERVICE = "com.system76.PowerDaemon"
+SYSTEM76_POWER_KEYBOARD_INTERFACE = "com.system76.PowerDaemon.Keyboard"
+DBUS_TIMEOUT = 5
+# the zones the older System76 EC driver has color attributes for
+EC_ZONES = ["left", "center", "right", "extra"]
+LED_ATTRIBUTES = [
+    "brightness", "max_brightness", "brightness_hw_changed",
+    "color", "color_left", "color_center", "color_right", "color_extra", "multi_intensity",
+]
+# where older tuxedo_keyboard versions put the keyboard backlight, outside the LED class
+TUXEDO_PLATFORM_PATH = pathlib.PosixPath("/sys/devices/platform/tuxedo_keyboard")
+SYSTEM_SLEEP_PATH = pathlib.PosixPath("/usr/lib/systemd/system-sleep")
+UNIT_PATH = pathlib.PosixPath("/etc/systemd/system")
+HOOK_NAME = "s76-kbd-led-statemgr"
+HOOK_MARKER = "# Installed by s76-kbd-led-statemgr install-hooks; remove with uninstall-hooks"
+DEFAULT_PALETTE = ["FF0000", "FFFF00", "00FF00", "00FFFF", "0000FF", "FF00FF", "FFFFFF"]
+# the CSS named colors, which are the X11 colors with a few web-specific differences
+COLOR_NAMES = {
+    "aliceblue": "F0F8FF", "antiquewhite": "FAEBD7", "aqua": "00FFFF", "aquamarine": "7FFFD4", "azure": "F0FFFF",
+    "beige": "F5F5DC", "bisque": "FFE4C4", "black": "000000", "blanchedalmond": "FFEBCD", "blue": "0000FF",
+    "blueviolet": "8A2BE2", "brown": "A52A2A", "burlywood": "DEB887", "cadetblue": "5F9EA0", "chartreuse": "7FFF00",
+    "chocolate": "D2691E", "coral": "FF7F50", "cornflowerblue": "6495ED", "cornsilk": "FFF8DC", "crimson": "DC143C",
+    "cyan": "00FFFF", "darkblue": "00008B", "darkcyan": "008B8B", "darkgoldenrod": "B8860B", "darkgray": "A9A9A9",
+    "darkgreen": "006400", "darkgrey": "A9A9A9", "darkkhaki": "BDB76B", "darkmagenta": "8B008B",
+    "darkolivegreen": "556B2F", "darkorange": "FF8C00", "darkorchid": "9932CC", "darkred": "8B0000",
+    "darksalmon": "E9967A", "darkseagreen": "8FBC8F", "darkslateblue": "483D8B", "darkslategray": "2F4F4F",
+    "darkslategrey": "2F4F4F", "darkturquoise": "00CED1", "darkviolet": "9400D3", "deeppink": "FF1493",
+    "deepskyblue": "00BFFF", "dimgray": "696969", "dimgrey": "696969", "dodgerblue": "1E90FF",
+    "firebrick": "B22222", "floralwhite": "FFFAF0", "forestgreen": "228B22", "fuchsia": "FF00FF",
+    "gainsboro": "DCDCDC", "ghostwhite": "F8F8FF", "gold": "FFD700", "goldenrod": "DAA520", "gray": "808080",
+    "green": "008000", "greenyellow": "ADFF2F", "grey": "808080", "honeydew": "F0FFF0", "hotpink": "FF69B4",
+    "indianred": "CD5C5C", "indigo": "4B0082", "ivory": "FFFFF0", "khaki": "F0E68C", "lavender": "E6E6FA",
+    "lavenderblush": "FFF0F5", "lawngreen": "7CFC00", "lemonchiffon": "FFFACD", "lightblue": "ADD8E6",
+    "lightcoral": "F08080", "lightcyan": "E0FFFF", "lightgoldenrodyellow": "FAFAD2", "lightgray": "D3D3D3",
+    "lightgreen": "90EE90", "lightgrey": "D3D3D3", "lightpink": "FFB6C1", "lightsalmon": "FFA07A",
+    "lightseagreen": "20B2AA", "lightskyblue": "87CEFA", "lightslategray": "778899", "lightslategrey": "778899",
+    "lightsteelblue": "B0C4DE", "lightyellow": "FFFFE0", "lime": "00FF00", "limegreen": "32CD32", "linen": "FAF0E6",
+    "magenta": "FF00FF", "maroon": "800000", "mediumaquamarine": "66CDAA", "mediumblue": "0000CD",
+    "mediumorchid": "BA55D3", "mediumpurple": "9370DB", "mediumseagreen": "3CB371", "mediumslateblue": "7B68EE",
+    "mediumspringgreen": "00FA9A", "mediumturquoise": "48D1CC", "mediumvioletred": "C71585",
+    "midnightblue": "191970", "mintcream": "F5FFFA", "mistyrose": "FFE4E1", "moccasin": "FFE4B5",
+    "navajowhite": "FFDEAD", "navy": "000080", "oldlace": "FDF5E6", "olive": "808000", "olivedrab": "6B8E23",
+    "orange": "FFA500", "orangered": "FF4500", "orchid": "DA70D6", "palegoldenrod": "EEE8AA", "palegreen": "98FB98",
+    "paleturquoise": "AFEEEE", "palevioletred": "DB7093", "papayawhip": "FFEFD5", "peachpuff": "FFDAB9",
+    "peru": "CD853F", "pink": "FFC0CB", "plum": "DDA0DD", "powderblue": "B0E0E6", "purple": "800080",
+    "rebeccapurple": "663399", "red": "FF0000", "rosybrown": "BC8F8F", "royalblue": "4169E1",
+    "saddlebrown": "8B4513", "salmon": "FA8072", "sandybrown": "F4A460", "seagreen": "2E8B57", "seashell": "FFF5EE",
+    "sienna": "A0522D", "silver": "C0C0C0", "skyblue": "87CEEB", "slateblue": "6A5ACD", "slategray": "708090",
+    "slategrey": "708090", "snow": "FFFAFA", "springgreen": "00FF7F", "steelblue": "4682B4", "tan": "D2B48C",
+    "teal": "008080", "thistle": "D8BFD8", "tomato": "FF6347", "turquoise": "40E0D0", "violet": "EE82EE",
+    "wheat": "F5DEB3", "white": "FFFFFF", "whitesmoke": "F5F5F5", "yellow": "FFFF00", "yellowgreen": "9ACD32",
+}
+
+
+EXIT_FAILURE = 1
+EXIT_USAGE = 2  # also what argparse exits with
+EXIT_CONFIGURATION = 3
+EXIT_STATE = 4
+EXIT_DEVICE = 5
+EXIT_PERMISSION = 6
+
+
+class StatemgrError(RuntimeError):
+    exit_code = EXIT_FAILURE
+
+
+class UsageError(StatemgrError):
+    exit_code = EXIT_USAGE
+
+
+class ConfigurationError(StatemgrError):
+    exit_code = EXIT_CONFIGURATION
+
+
+class StateError(StatemgrError):
+    exit_code = EXIT_STATE
+
+
+class DeviceError(StatemgrError):
+    exit_code = EXIT_DEVICE
+
+
+class PermissionDeniedError(StatemgrError):
+    exit_code = EXIT_PERMISSION
+
+
+class ValidationError(StatemgrError):
+    def __init__(self, message: str, problems: list, reported: bool = False, exit_code: int = EXIT_CONFIGURATION):
+        super().__init__(message)
+        self.problems = problems
+        self.reported = reported
+        self.exit_code = exit_code
+
+
+def os_error(error_class: type, message: str, e: OSError) -> StatemgrError:
+    if e.errno in (errno.EACCES, errno.EPERM, errno.EROFS):
+        return PermissionDeniedError(f"{message}: {e.strerror}")
+    return error_class(f"{message}: {e.strerror}")
+
+
+def user_configuration_paths() -> list:
+    # per-user preferences, only consulted when running unprivileged
+    config_home = os.environ.get("XDG_CONFIG_HOME") or os.path.expanduser("~/.config")
+    directory = pathlib.PosixPath(config_home) / "s76-kbd-led-statemgr"
+    return [directory / f"config.{suffix}" for suffix in ("toml", "yaml", "json")]
+
+
+def configuration_paths() -> list:
+    if os.geteuid() == 0:
+        return list(CONFIGURATION_PATHS)
+    return user_configuration_paths() + CONFIGURATION_PATHS
+
+
+def load_configuration_file(path, including: tuple = ()) -> dict:
+    # TOML for .toml files, YAML for .yaml and .yml, JSON for anything else. 'including' is the chain of files
+    # whose 'include' led here, to catch loops
+    suffix = pathlib.PosixPath(path).suffix
+    with open(path, "rb") as config_file:
+        if suffix == ".toml":
+            if tomllib is None:
+                raise ValueError("reading TOML needs Python 3.11 or later, or the tomli package")
+            configuration = tomllib.load(config_file)
+        elif suffix in (".yaml", ".yml"):
+            if yaml is None:
+                raise ValueError("reading YAML needs the PyYAML package")
+            try:
+                configuration = yaml.safe_load(config_file)
+            except yaml.YAMLError as e:
+                raise ValueError(str(e))
+        else:
+            configuration = json.load(config_file)
+    configuration = migrate_configuration(configuration)
+    if type(configuration) is dict and "include" in configuration:
+        configuration = include_configuration(path, configuration, including)
+    return configuration
+
+
+def include_configuration(path, configuration: dict, including: tuple) -> dict:
+    # the fragments named by 'include' merged in order, with the including file's own settings on top. Relative
+    # paths are relative to the including file, and glob patterns include every match in sorted order.
+    includes = configuration.pop("include")
+    if type(includes) is str:
+        includes = [includes]
+    if type(includes) is not list or not all(type(include) is str for include in includes):
+        raise ValueError("'include' is not a list of paths")
+    chain = including + (os.path.realpath(path),)
+    merged = {}
+    for include in includes:
+        pattern = os.path.join(os.path.dirname(path), expand_path(include))
+        for fragment_path in sorted(glob.glob(pattern)) if re.search(r"[*?[]", pattern) else [pattern]:
+            if os.path.realpath(fragment_path) in chain:
+                raise ValueError(f"'{fragment_path}' is included in a loop")
+            try:
+                fragment = load_configuration_file(fragment_path, chain)
+            except OSError as e:
+                raise ValueError(f"unable to read included '{fragment_path}': {e.strerror}")
+            if type(fragment) is not dict:
+                raise ValueError(f"included '{fragment_path}' is not an object")
+            merge_configuration(merged, fragment)
+            logger.info(f"Included configuration from '{fragment_path}'")
+    merge_configuration(merged, configuration)
+    return merged
+
+
+def migrate_document(document, kind: str, current_version: int, migrations: list):
+    # upgrade a configuration or state document, in memory, one version at a time
+    if type(document) is not dict:
+        return document
+    version = document.get("version", 0)
+    if type(version) is not int or version < 0:
+        raise ValueError(f"{kind} version {version!r} is not a whole number")
+    if version > current_version:
+        raise ValueError(f"{kind} version {version} is newer than this tool understands ({current_version})")
+    for migration in migrations[version:current_version]:
+        document = migration(document)
+    if version < current_version:
+        logger.debug(f"Upgraded {kind} from version {version} to {current_version}")
+    document["version"] = current_version
+    return document
+
+
+def migrate_configuration(configuration):
+    return migrate_document(configuration, "configuration", CONFIGURATION_VERSION, CONFIGURATION_MIGRATIONS)
+
+
+def migrate_state(state):
+    return migrate_document(state, "state", STATE_VERSION, STATE_MIGRATIONS)
+
+
+# entry n upgrades a version n document to version n + 1. Drop-ins are migrated on their own, so a
+# configuration migration has to cope with any table being absent.
+CONFIGURATION_MIGRATIONS = [
+    # 0 to 1: unversioned documents differ only by lacking the version
+    lambda configuration: configuration,
+]
+STATE_MIGRATIONS = [
+    lambda state: state,
+    # 1 to 2: values written by hand or by other tools, such as numeric brightness or "#3366cc", are normalized
+    lambda state: normalize_state_values(state),
+    # 2 to 3: with several devices configured, the state may instead hold each one's under 'devices'
+    lambda state: state,
+]
+
+
+def normalize_state_values(state: dict) -> dict:
+    # the string forms the state file holds, in the state itself and the 'previous' it carries
+    for document in (state, state.get("previous")):
+        if type(document) is not dict:
+            continue
+        for key in ("brightness", "last_brightness"):
+            if type(document.get(key)) is int:
+                document[key] = str(document[key])
+        if type(document.get("color")) is str:
+            document["color"] = parse_color(document["color"])
+        if type(document.get("zones")) is dict:
+            document["zones"] = {
+                zone: parse_color(color) if type(color) is str else color for zone, color in document["zones"].items()
+            }
+    return state
+
+
+def read_configuration(override_path: str = None, lenient: bool = False, validate: bool = True) -> dict:
+    configuration, sources = load_configuration(override_path, lenient=lenient)
+    configuration = normalize_configuration(apply_environment(configuration))
+    if validate:
+        # paths are left to check-config, as a missing attribute should not stop e.g. 'status' from explaining
+        problems = validate_configuration(configuration, check_paths=False)
+        if problems and not lenient:
+            source = ", ".join(f"'{path}'" for path in sources) or "the built-in defaults"
+            raise ValidationError(f"{len(problems)} configuration problem(s) found in {source}", problems)
+        for problem in problems:
+            logger.warning(f"Ignoring configuration problem: {problem}")
+    return configuration
+
+
+def load_configuration(override_path: str = None, lenient: bool = False) -> tuple:
+    # returns the configuration and the files it came from
+    if override_path is not None:
+        # an explicitly requested configuration must load, rather than silently falling back to defaults
+        try:
+            configuration = load_configuration_file(override_path)
+            logger.info(f"Loaded configuration from '{override_path}'")
+            return configuration, [override_path]
+        except OSError as e:
+            raise os_error(ConfigurationError, f"Unable to read configuration '{override_path}'", e)
+        except ValueError as e:
+            raise ConfigurationError(f"Unable to parse configuration '{override_path}': {e}")
     configuration = None
-    for config_path in configuration_paths:
-        # noinspection PyBroadException
+    sources = []
+    for config_path in configuration_paths():
+        if not config_path.exists():
+            continue
+        # a file that exists but cannot be used is an error, not a reason to quietly use the defaults
         try:
-            with open(config_path, "rt") as config_file:
-                configuration = json.load(config_file)
-            break
-        except Exception:
+            configuration = load_configuration_file(config_path)
+        except (OSError, ValueError) as e:
+            if not lenient:
+                raise ConfigurationError(f"Unable to use configuration '{config_path}': {e}")
+            logger.warning(f"Skipping configuration '{config_path}': {e}")
+            continue
+        logger.info(f"Loaded configuration from '{config_path}'")
+        sources.append(str(config_path))
+        break
+    if configuration is None:
+        logger.info("No configuration file found, using built-in defaults")
+        configuration = copy.deepcopy(DEFAULT_CONFIGURATION)
+        if not os.path.exists(configuration["brightness"]["path"]):
+            discovered = preferred_device(discover_devices(LEDS_PATH))
+            if discovered is not None:
+                logger.info(f"Using the keyboard backlight at '{discovered['path']}'")
+                for key, table in discovered_configuration(discovered).items():
+                    configuration[key].update(table)
+    for drop_in_path in sorted(DROP_IN_PATH.glob("*")) if DROP_IN_PATH.is_dir() else []:
+        if drop_in_path.suffix not in (".json", ".toml", ".yaml", ".yml"):
+            continue
+        try:
+            drop_in = load_configuration_file(drop_in_path)
+            if type(drop_in) is not dict or type(configuration) is not dict:
+                raise ValueError("not an object")
+        except (OSError, ValueError) as e:
+            if not lenient:
+                raise ConfigurationError(f"Unable to use drop-in configuration '{drop_in_path}': {e}")
+            logger.warning(f"Skipping drop-in configuration '{drop_in_path}': {e}")
+            continue
+        merge_configuration(configuration, drop_in)
+        logger.info(f"Merged drop-in configuration from '{drop_in_path}'")
+        sources.append(str(drop_in_path))
+    return configuration, sources
+
+
+def merge_configuration(configuration: dict, overlay: dict):
+    # tables are merged key by key, anything else (including lists) is replaced outright
+    for key, value in overlay.items():
+        if type(value) is dict and type(configuration.get(key)) is dict:
+            merge_configuration(configuration[key], value)
+        else:
+            configuration[key] = copy.deepcopy(value)
+
+
+def apply_environment(configuration):
+    # environment variables win over the configuration file, for tests and one-off debugging
+    if type(configuration) is not dict:
+        return configuration
+    for variable, (section, key) in ENVIRONMENT_OVERRIDES.items():
+        value = os.environ.get(variable)
+        if value is None:
+            continue
+        logger.info(f"Using {variable}={value!r} from the environment")
+        if key == "step" and value.isdigit():
+            value = int(value)
+        # device settings go to the first device
+        table = configuration if section is None else next(iter(device_tables(configuration).values()), {})
+        if section is None:
+            table[key] = value
+        elif type(table.get(section)) is dict:
+            table[section][key] = value
+        else:
+            table[section] = {key: value}
+    return configuration
+
+
+def configuration_file(override_path: str = None) -> pathlib.PosixPath:
+    # the file read_configuration() would load, or where a new one belongs when there is none
+    if override_path is not None:
+        return pathlib.PosixPath(override_path)
+    for config_path in configuration_paths():
+        if config_path.exists():
+            return config_path
+    return CONFIGURATION_PATHS[0]
+
+
+def device_tables(configuration: dict) -> dict:
+    # the brightness/color tables of each device, whether they sit at the top level or under 'devices'
+    if type(configuration.get("devices")) is dict:
+        return {name: device for name, device in configuration["devices"].items() if type(device) is dict}
+    return {"": configuration}
+
+
+def expand_path(value: str) -> str:
+    # ${NAME} environment variables (the XDG ones with their usual fallbacks) and systemd-style %h, %u and %%
+    fallbacks = {
+        "HOME": os.path.expanduser("~"),
+        "XDG_CONFIG_HOME": os.path.expanduser("~/.config"),
+        "XDG_STATE_HOME": os.path.expanduser("~/.local/state"),
+        "XDG_RUNTIME_DIR": f"/run/user/{os.geteuid()}",
+    }
+
+    def variable(match):
+        name = match.group(1)
+        if os.environ.get(name):
+            return os.environ[name]
+        if name in fallbacks:
+            return fallbacks[name]
+        logger.warning(f"'{value}' refers to ${{{name}}}, which is not set")
+        return match.group(0)
+
+    specifiers = {"h": os.path.expanduser("~"), "u": getpass.getuser(), "%": "%"}
+    value = re.sub(r"\$\{(\w+)\}", variable, value)
+    return re.sub(r"%([hu%])", lambda match: specifiers[match.group(1)], value)
+
+
+def resolve_path(value: str) -> str:
+    # a path with glob patterns, e.g. /sys/class/leds/*::kbd_backlight/brightness, resolved to the file it matches,
+    # preferring the System76 drivers in DRIVER_MODULES order; a pattern matching nothing is left for validation
+    # or the read/write to report
+    if not re.search(r"[*?[]", value):
+        return value
+    matches = glob.glob(value)
+    if not matches:
+        logger.debug(f"'{value}' matches nothing")
+        return value
+
+    def preference(match):
+        driver = pathlib.PosixPath(match).parent.name.split("::")[0]
+        return DRIVER_MODULES.index(driver) if driver in DRIVER_MODULES else len(DRIVER_MODULES), match
+
+    matches.sort(key=preference)
+    if len(matches) > 1:
+        logger.info(f"'{value}' matches {len(matches)} files, using '{matches[0]}'")
+    else:
+        logger.debug(f"'{value}' resolved to '{matches[0]}'")
+    return matches[0]
+
+
+def first_existing_path(candidates: list) -> str:
+    # for a list of paths, e.g. one per driver generation: the first that exists, or else the first one, for
+    # validation or the read/write to report
+    paths = [resolve_path(expand_path(candidate)) for candidate in candidates]
+    for path in paths:
+        if os.path.exists(path):
+            logger.debug(f"Using '{path}', the first of {len(paths)} candidate paths that exists")
+            return path
+    logger.debug(f"None of the candidate paths {', '.join(paths)} exist")
+    return paths[0]
+
+
+def schedule_time(value: str) -> str:
+    # the zero-padded HH:MM form, so that times sort, or None
+    try:
+        return datetime.datetime.strptime(value, "%H:%M").strftime("%H:%M")
+    except (TypeError, ValueError):
+        return None
+
+
+def normalize_configuration(configuration):
+    # convert color names to the hex form sysfs expects, and expand variables and glob patterns in paths, leaving
+    # anything malformed for validation to report
+    if type(configuration) is not dict:
+        return configuration
+    for key, variable in (("state_path", "STATE_DIRECTORY"), ("runtime_state_path", "RUNTIME_DIRECTORY")):
+        if type(configuration.get(key)) is not str:
+            continue
+        configuration[key] = expand_path(configuration[key])
+        if configuration[key] and not os.path.isabs(configuration[key]):
+            # relative to the directory systemd makes for a unit's StateDirectory= or RuntimeDirectory= (the first,
+            # if it names several), or where it would make it, for the sleep hook running outside the unit
+            directory = os.environ.get(variable, "").split(":")[0] or RELATIVE_STATE_DIRECTORIES[key]
+            configuration[key] = os.path.join(directory, configuration[key])
+    for device in device_tables(configuration).values():
+        match_device(device)
+        tables = [device.get(key) for key in ("brightness", "color")]
+        tables += list(device["zones"].values()) if type(device.get("zones")) is dict else []
+        for table in tables:
+            if type(table) is dict and type(table.get("path")) is str:
+                if re.search(r"[*?[]", table["path"]):
+                    table["configured_path"] = table["path"]
+                table["path"] = resolve_path(expand_path(table["path"]))
+            elif type(table) is dict and type(table.get("path")) is list and table["path"]:
+                if all(type(candidate) is str for candidate in table["path"]):
+                    table["configured_path"] = table["path"]
+                    table["path"] = first_existing_path(table["path"])
+        detect_zones(configuration, device)
+        color = device.get("color")
+        if type(color) is dict:
+            if color.get("enabled") is False:
+                # an unmanaged color still needs a value for the state to carry
+                color.setdefault("default", DEFAULT_CONFIGURATION["color"]["default"])
+            if type(color.get("default")) is str:
+                color["default"] = parse_color(color["default"])
+            for key in ("palette", "allowed"):
+                if type(color.get(key)) is list:
+                    color[key] = [parse_color(entry) if type(entry) is str else entry for entry in color[key]]
+        zones = device.get("zones")
+        for settings in zones.values() if type(zones) is dict else []:
+            if type(settings) is dict and type(settings.get("default")) is str:
+                settings["default"] = parse_color(settings["default"])
+    profiles = configuration.get("profiles")
+    if type(profiles) is dict:
+        for profile in profiles.values():
+            if type(profile) is dict and type(profile.get("color")) is str:
+                profile["color"] = parse_color(profile["color"])
+            if type(profile) is dict and type(profile.get("zones")) is dict:
+                profile["zones"] = {
+                    zone: parse_color(color) if type(color) is str else color
+                    for zone, color in profile["zones"].items()
+                }
+    schedule = configuration.get("schedule")
+    if type(schedule) is dict:
+        configuration["schedule"] = {schedule_time(at) or at: entry for at, entry in schedule.items()}
+        for entry in configuration["schedule"].values():
+            if type(entry) is dict and type(entry.get("color")) is str:
+                entry["color"] = parse_color(entry["color"])
+    return configuration
+
+
+def match_device(device: dict, quiet: bool = False):
+    # 'match' finds the LED class device by its driver, its parent device and its name (glob patterns, as
+    # discovered), replacing brightness.path and color.path, so the configuration keeps working when a kernel
+    # version names the LED or its sysfs path differently. 'quiet' is for looking again, as 'restore' does while
+    # waiting for the device.
+    match = device.get("match")
+    if type(match) is not dict or not all(type(pattern) is str for pattern in match.values()):
+        return
+    candidates = [
+        found
+        for found in discover_devices(LEDS_PATH, keyboards_only=False)
+        if all(fnmatch.fnmatchcase(found.get(key) or "", pattern) for key, pattern in match.items())
+    ]
+    found = preferred_device(candidates)
+    if found is None:
+        described = ", ".join(f"{key} {pattern!r}" for key, pattern in match.items())
+        unconfigured = [
+            key
+            for key in ("brightness", "color")
+            if type(device.get(key)) is dict and device[key].get("path") in (None, str(UNMATCHED_PATH / key))
+        ]
+        for key in unconfigured:
+            # without paths to fall back on, the device is missing for now, as it can be just after resume
+            device[key]["path"] = str(UNMATCHED_PATH / key)
+        fallback = "treating the device as missing" if unconfigured else "using the configured paths"
+        (logger.debug if quiet else logger.warning)(f"No LED device in {LEDS_PATH} matches {described}, {fallback}")
+        return
+    logger.debug(f"Using the LED device at '{found['path']}' for {match}")
+    discovered = discovered_configuration(found)
+    for key in ("brightness", "color"):
+        if type(device.get(key)) is dict:
+            device[key]["path"] = discovered[key]["path"]
+    if type(device.get("color")) is dict:
+        device["color"].setdefault("format", discovered["color"]["format"])
+
+
+def locate_device(configuration: dict):
+    # looks for a device's LED again, as loading the configuration did, for one that was missing then, e.g. while
+    # the EC driver registers it again after resume: glob patterns and lists of candidates are resolved anew, then
+    # 'match' is matched again
+    tables = [configuration.get(key) for key in ("brightness", "color")]
+    tables += list(configuration["zones"].values()) if type(configuration.get("zones")) is dict else []
+    for table in tables:
+        if type(table) is not dict or "configured_path" not in table:
+            continue
+        if type(table["configured_path"]) is list:
+            table["path"] = first_existing_path(table["configured_path"])
+        else:
+            table["path"] = resolve_path(expand_path(table["configured_path"]))
+    match_device(configuration, quiet=True)
+    detect_zones(configuration, configuration)
+
+
+def detect_zones(configuration: dict, device: dict):
+    # keyboards on the older System76 EC driver have color_left, color_center and so on instead of (or as well as)
+    # color; unless zones are configured (even as an empty table), they are managed as found
+    brightness = device.get("brightness")
+    if "zones" in device or "zones" in configuration or type(brightness) is not dict:
+        return
+    if type(brightness.get("path")) is not str:
+        return
+    directory = pathlib.PosixPath(brightness["path"]).parent
+    found = [zone for zone in EC_ZONES if (directory / f"color_{zone}").exists()]
+    if not found:
+        return
+    color = device.get("color") if type(device.get("color")) is dict else configuration.get("color")
+    default = color.get("default") if type(color) is dict else None
+    device["zones"] = {
+        zone: {"default": default if type(default) is str else DEFAULT_CONFIGURATION["color"]["default"]}
+        for zone in found
+    }
+    logger.debug(f"Found color zones {', '.join(found)} in '{directory}'")
+    own_color = device.get("color")
+    if type(own_color) is dict and "enabled" not in own_color and type(own_color.get("path")) is str:
+        if not os.path.exists(own_color["path"]):
+            # only the zones have colors
+            own_color["enabled"] = False
+
+
+def unknown_settings(table: dict, known: list, prefix: str) -> list:
+    # catch typos, which would otherwise silently leave a setting at its default
+    problems = []
+    for key in table:
+        if key in known or key in DERIVED_SETTINGS:
+            continue
+        suggestion = difflib.get_close_matches(key, known, n=1)
+        problems.append(
+            f"'{prefix}{key}' is not a known setting" + (f", did you mean '{suggestion[0]}'?" if suggestion else "")
+        )
+    return problems
+
+
+def validate_backend(table: dict, prefix: str) -> list:
+    problems = []
+    backend = table.get("backend", "sysfs")
+    if backend not in BACKENDS:
+        backends = ", ".join(f'"{name}"' for name in BACKENDS)
+        problems.append(f"'{prefix}backend' {backend!r} is not one of {backends}")
+    openrgb = table.get("openrgb", {})
+    if type(openrgb) is not dict:
+        return problems + [f"'{prefix}openrgb' is not an object"]
+    problems += unknown_settings(openrgb, KNOWN_SETTINGS["openrgb"], f"{prefix}openrgb.")
+    for key in ("host", "controller"):
+        if type(openrgb.get(key, "")) is not str:
+            problems.append(f"'{prefix}openrgb.{key}' {openrgb[key]!r} is not a string")
+    port = openrgb.get("port", OPENRGB_DEFAULT_PORT)
+    if type(port) is not int or not 0 < port < 65536:
+        problems.append(f"'{prefix}openrgb.port' {port!r} is not a port number")
+    ite8291 = table.get("ite8291", {})
+    if type(ite8291) is not dict:
+        return problems + [f"'{prefix}ite8291' is not an object"]
+    problems += unknown_settings(ite8291, KNOWN_SETTINGS["ite8291"], f"{prefix}ite8291.")
+    if type(ite8291.get("path", "")) is not str:
+        problems.append(f"'{prefix}ite8291.path' {ite8291['path']!r} is not a path")
+    system76_power = table.get("system76_power", {})
+    if type(system76_power) is not dict:
+        return problems + [f"'{prefix}system76_power' is not an object"]
+    problems += unknown_settings(system76_power, KNOWN_SETTINGS["system76_power"], f"{prefix}system76_power.")
+    if type(system76_power.get("object", "/")) is not str or not system76_power.get("object", "/").startswith("/"):
+        problems.append(f"'{prefix}system76_power.object' {system76_power['object']!r} is not a D-Bus object path")
+    mock = table.get("mock", {})
+    if type(mock) is not dict:
+        return problems + [f"'{prefix}mock' is not an object"]
+    problems += unknown_settings(mock, KNOWN_SETTINGS["mock"], f"{prefix}mock.")
+    if type(mock.get("path", "")) is not str:
+        problems.append(f"'{prefix}mock.path' {mock['path']!r} is not a path")
+    max_brightness = mock.get("max_brightness", 255)
+    if type(max_brightness) is not int or not 0 < max_brightness <= 255:
+        problems.append(f"'{prefix}mock.max_brightness' {max_brightness!r} is not a number from 1 to 255")
+    return problems
+
+
+def validate_device(device: dict, prefix: str, check_paths: bool, backend: str = "sysfs") -> list:
+    # 'backend' is the one a device without its own inherits
+    problems = unknown_settings(device, KNOWN_SETTINGS["device"], prefix) if prefix else []
+    problems += validate_backend(device, prefix)
+    backend = BACKENDS.get(device.get("backend", backend), BACKENDS["sysfs"])
+    match = device.get("match", {})
+    if type(match) is not dict:
+        problems.append(f"'{prefix}match' is not an object")
+        match = {}
+    problems += unknown_settings(match, KNOWN_SETTINGS["match"], f"{prefix}match.")
+    for key, pattern in match.items():
+        if type(pattern) is not str:
+            problems.append(f"'{prefix}match.{key}' {pattern!r} is not a pattern")
+    if match and not backend.uses_paths:
+        problems.append(f"'{prefix}match' is not supported by the {backend.name} backend")
+    zones = device.get("zones", {})
+    if type(zones) is not dict:
+        problems.append(f"'{prefix}zones' is not an object")
+        zones = {}
+    if zones and not backend.supports_zones:
+        problems.append(f"'{prefix}zones' are not supported by the {backend.name} backend")
+    for zone, settings in zones.items():
+        if re.fullmatch(r"[a-z]+", zone) is None:
+            problems.append(f"'{prefix}zones.{zone}' is not a lower-case zone name such as 'left'")
+        if type(settings) is not dict:
+            problems.append(f"'{prefix}zones.{zone}' is not an object")
+            continue
+        problems += unknown_settings(settings, KNOWN_SETTINGS["zone"], f"{prefix}zones.{zone}.")
+        default = settings.get("default")
+        if type(default) is not str or not valid_color(default):
+            problems.append(f"'{prefix}zones.{zone}.default' {default!r} is not a valid color")
+        path = settings.get("path", "")
+        if type(path) is not str:
+            problems.append(f"'{prefix}zones.{zone}.path' is not a path or a list of paths")
+        elif check_paths and path and not os.access(path, os.R_OK | os.W_OK):
+            problems.append(f"'{prefix}zones.{zone}.path' {path} is not readable and writable")
+    for key, valid in (("brightness", valid_brightness_setting), ("color", valid_color)):
+        section = device.get(key)
+        if type(section) is not dict:
+            problems.append(f"'{prefix}{key}' is missing or not an object")
+            continue
+        problems += unknown_settings(section, KNOWN_SETTINGS[key], f"{prefix}{key}.")
+        if key == "color" and type(section.get("enabled", True)) is not bool:
+            problems.append(f"'{prefix}color.enabled' {section['enabled']!r} is not true or false")
+        if key == "color" and section.get("enabled") is False:
+            continue
+        limit = section.get("max", "255")
+        if key == "brightness" and (type(limit) is not str or not valid_brightness_setting(limit)):
+            problems.append(f"'{prefix}brightness.max' {limit!r} is not a valid brightness")
+        path = section.get("path")
+        if not backend.uses_paths and path is None:
+            pass
+        elif "match" in device and path is None:
+            # found when the command runs
             pass
-    return configuration if configuration is not None else default_config
+        elif type(path) is not str or len(path) == 0:
+            problems.append(f"'{prefix}{key}.path' is missing, or not a path or a list of paths")
+        elif not check_paths:
+            pass
+        elif not os.path.exists(path):
+            problems.append(f"'{prefix}{key}.path' {path} does not exist")
+        elif not os.access(path, os.R_OK | os.W_OK):
+            problems.append(f"'{prefix}{key}.path' {path} is not readable and writable")
+        default = section.get("default")
+        if type(default) is not str or not valid(default):
+            problems.append(f"'{prefix}{key}.default' {default!r} is not a valid {key}")
+        step = section.get("step", 16)
+        if key == "brightness" and (type(step) is not int or step <= 0):
+            problems.append(f"'{prefix}brightness.step' {step!r} is not a positive integer")
+        levels = section.get("levels", ["0"])
+        if key == "brightness" and (
+            type(levels) is not list
+            or len(levels) == 0
+            or not all(type(level) is str and valid_brightness(level) for level in levels)
+            or [int(level) for level in levels] != sorted({int(level) for level in levels})
+        ):
+            problems.append(f"'{prefix}brightness.levels' {levels!r} is not an ascending list of brightness values")
+        if key == "color":
+            allowed = section.get("allowed", [])
+            if type(allowed) is not list or not all(type(entry) is str and valid_color(entry) for entry in allowed):
+                problems.append(f"'{prefix}color.allowed' is not a list of valid colors")
+                allowed = []
+            if section.get("enforce", "reject") not in ("reject", "nearest"):
+                problems.append(f"'{prefix}color.enforce' {section['enforce']!r} is not \"reject\" or \"nearest\"")
+            if section.get("format", "hex") not in COLOR_FORMATS:
+                formats = ", ".join(f'"{name}"' for name in COLOR_FORMATS)
+                problems.append(f"'{prefix}color.format' {section['format']!r} is not one of {formats}")
+            if allowed and type(default) is str and default.upper() not in allowed:
+                problems.append(f"'{prefix}color.default' {default!r} is not one of 'color.allowed'")
+            palette = section.get("palette", allowed or DEFAULT_PALETTE)
+            if type(palette) is not list or len(palette) == 0:
+                problems.append(f"'{prefix}color.palette' is not a non-empty list")
+            else:
+                for entry in palette:
+                    if type(entry) is not str or not valid_color(entry):
+                        problems.append(f"'{prefix}color.palette' entry {entry!r} is not a valid color")
+                    elif allowed and entry.upper() not in allowed:
+                        problems.append(f"'{prefix}color.palette' entry {entry!r} is not one of 'color.allowed'")
+    return problems
+
+
+def validate_configuration(configuration, check_paths: bool = True) -> list:
+    if type(configuration) is not dict:
+        return ["configuration is not an object"]
+    problems = unknown_settings(configuration, KNOWN_SETTINGS[None], "")
+    if "devices" in configuration:
+        problems += validate_backend(configuration, "")
+    if "devices" not in configuration:
+        problems += validate_device(configuration, "", check_paths)
+    elif "brightness" in configuration or "color" in configuration:
+        problems.append("'brightness' and 'color' belong under 'devices' once it is used")
+    elif type(configuration["devices"]) is not dict or len(configuration["devices"]) == 0:
+        problems.append("'devices' is not a non-empty object")
+    else:
+        for name, device in configuration["devices"].items():
+            if type(device) is not dict:
+                problems.append(f"'devices.{name}' is not an object")
+                continue
+            backend = configuration.get("backend", "sysfs")
+            problems += validate_device(device, f"devices.{name}.", check_paths, backend)
+    profiles = configuration.get("profiles", {})
+    if type(profiles) is not dict:
+        problems.append("'profiles' is not an object")
+    else:
+        for name, profile in profiles.items():
+            if type(profile) is not dict or not ({"brightness", "color", "zones"} & set(profile)):
+                problems.append(f"'profiles.{name}' is not an object with a brightness, color and/or zones")
+                continue
+            problems += unknown_settings(profile, KNOWN_SETTINGS["profile"], f"profiles.{name}.")
+            zones = profile.get("zones", {})
+            if type(zones) is not dict:
+                problems.append(f"'profiles.{name}.zones' is not an object")
+                zones = {}
+            for zone, color in zones.items():
+                if type(color) is not str or not valid_color(color):
+                    problems.append(f"'profiles.{name}.zones.{zone}' {color!r} is not a valid color")
+            brightness = profile.get("brightness", "0")
+            if type(brightness) is not str or not valid_brightness_setting(brightness):
+                problems.append(f"'profiles.{name}.brightness' {brightness!r} is not a valid brightness")
+            color = profile.get("color", "000000")
+            if type(color) is not str or not valid_color(color):
+                problems.append(f"'profiles.{name}.color' {color!r} is not a valid color")
+    power = configuration.get("power", {})
+    if type(power) is not dict:
+        problems.append("'power' is not an object")
+    else:
+        problems += unknown_settings(power, KNOWN_SETTINGS["power"], "power.")
+        for source in ("ac", "battery"):
+            if source in power and (type(profiles) is not dict or power[source] not in profiles):
+                problems.append(f"'power.{source}' {power[source]!r} is not one of the configured profiles")
+        if "path" in power and type(power["path"]) is not str:
+            problems.append("'power.path' is not a string")
+    schedule = configuration.get("schedule", {})
+    if type(schedule) is not dict:
+        problems.append("'schedule' is not an object")
+        schedule = {}
+    for at, entry in schedule.items():
+        if schedule_time(at) is None:
+            problems.append(f"'schedule.{at}' is not a time of day in HH:MM form")
+        if type(entry) is not dict or not (set(KNOWN_SETTINGS["schedule"]) & set(entry)):
+            problems.append(f"'schedule.{at}' is not an object with a profile, brightness and/or color")
+            continue
+        problems += unknown_settings(entry, KNOWN_SETTINGS["schedule"], f"schedule.{at}.")
+        if "profile" in entry and (type(profiles) is not dict or entry["profile"] not in profiles):
+            problems.append(f"'schedule.{at}.profile' {entry['profile']!r} is not one of the configured profiles")
+        brightness = entry.get("brightness", "0")
+        if type(brightness) is not str or not valid_brightness_setting(brightness):
+            problems.append(f"'schedule.{at}.brightness' {brightness!r} is not a valid brightness")
+        color = entry.get("color", "000000")
+        if type(color) is not str or not valid_color(color):
+            problems.append(f"'schedule.{at}.color' {color!r} is not a valid color")
+    for key in ("restore_delay_ms", "reapply_delay_ms", "wait_for_device_ms", "retry_interval_ms"):
+        delay = configuration.get(key, 0)
+        if type(delay) is not int or delay < 0:
+            problems.append(f"'{key}' {delay!r} is not a number of milliseconds")
+    history = configuration.get("history", DEFAULT_HISTORY)
+    if type(history) is not int or history < 0:
+        problems.append(f"'history' {history!r} is not a number of states to keep")
+    backups = configuration.get("backups", 0)
+    if type(backups) is not int or backups < 0:
+        problems.append(f"'backups' {backups!r} is not a number of copies to keep")
+    if "state_max_age" in configuration and duration_seconds(configuration["state_max_age"]) is None:
+        problems.append(f"'state_max_age' {configuration['state_max_age']!r} is not a duration such as \"7d\"")
+    retries = configuration.get("retries", DEFAULT_RETRIES)
+    if type(retries) is not int or retries < 0:
+        problems.append(f"'retries' {retries!r} is not a number of attempts")
+    for phase in ("pre", "post"):
+        switches = configuration.get(phase, {})
+        if type(switches) is not dict:
+            problems.append(f"'{phase}' is not an object")
+            continue
+        problems += unknown_settings(switches, KNOWN_SETTINGS[phase], f"{phase}.")
+        for key, value in switches.items():
+            if key in KNOWN_SETTINGS[phase] and type(value) is not bool:
+                problems.append(f"'{phase}.{key}' {value!r} is not true or false")
+    hooks = configuration.get("hooks", {})
+    if type(hooks) is not dict:
+        problems.append("'hooks' is not an object")
+        hooks = {}
+    problems += unknown_settings(hooks, KNOWN_SETTINGS["hooks"], "hooks.")
+    for name, command in hooks.items():
+        if not (type(command) is str or type(command) is list and all(type(part) is str for part in command)):
+            problems.append(f"'hooks.{name}' is not a command string or a list of arguments")
+    for key in ("state_mode", "state_directory_mode"):
+        mode = configuration.get(key, "0644")
+        if type(mode) is not str or not re.fullmatch(r"[0-7]?[0-7]{3}", mode):
+            problems.append(f"'{key}' {configuration[key]!r} is not an octal mode such as \"0640\"")
+    for key, lookup in (("state_owner", pwd.getpwnam), ("state_group", grp.getgrnam)):
+        if key not in configuration:
+            continue
+        try:
+            state_id(configuration[key], lookup)
+        except (KeyError, TypeError):
+            problems.append(f"'{key}' {configuration[key]!r} is not a known {key.split('_')[1]} name or id")
+    user_state_path = configuration.get("user_state_path", DEFAULT_USER_STATE_PATH)
+    if type(user_state_path) is not str or len(user_state_path) == 0:
+        problems.append("'user_state_path' is not a string")
+    runtime_state_path = configuration.get("runtime_state_path", DEFAULT_RUNTIME_STATE_PATH)
+    # None is how a run without the fallback (unprivileged, or with --state-file) is configured
+    if runtime_state_path is not None and (type(runtime_state_path) is not str or len(runtime_state_path) == 0):
+        problems.append("'runtime_state_path' is not a string")
+    state_path = configuration.get("state_path")
+    if type(state_path) is not str or len(state_path) == 0:
+        problems.append("'state_path' is missing or not a string")
+    elif check_paths:
+        # the state directory is created on demand, so check the closest ancestor that exists
+        state_path = pathlib.PosixPath(state_path)
+        existing = state_path if state_path.exists() else state_path.parent
+        while not existing.exists():
+            existing = existing.parent
+        if state_path.exists() and not state_path.is_file():
+            problems.append(f"'state_path' {state_path} is not a regular file")
+        elif not os.access(existing, os.W_OK):
+            problems.append(f"'state_path' {state_path} is not writable ({existing} is not writable)")
+    return problems
+
+
+def configured_devices(configuration: dict) -> dict:
+    # each device's settings merged with the shared ones, so that handlers only ever see a single device. Devices
+    # are named by their key under 'devices', or otherwise after their LED class directory
+    # (e.g. system76_acpi::kbd_backlight).
+    if "devices" not in configuration:
+        if configuration["brightness"].get("path") is None:
+            # e.g. a keyboard reached through OpenRGB, named after its backend
+            name = configuration.get("backend", "sysfs")
+        else:
+            name = pathlib.PosixPath(configuration["brightness"]["path"]).parent.name
+        return {name: dict(configuration, device=name)}
+    shared = {key: value for key, value in configuration.items() if key != "devices"}
+    devices = {}
+    for index, (name, device) in enumerate(configuration["devices"].items()):
+        # merged table by table as drop-ins are, so that e.g. a device's own 'mock' table with just max_brightness
+        # keeps the shared mock.path
+        devices[name] = copy.deepcopy(shared)
+        merge_configuration(devices[name], device)
+        # the first device takes over a state saved when it was the only one configured
+        devices[name].update(device=name, state_key=name, single_state=index == 0)
+    return devices
+
+
+def select_device(configuration: dict, name: str = None) -> dict:
+    # the named device, or the first configured one
+    devices = configured_devices(configuration)
+    if name is None:
+        return next(iter(devices.values()))
+    if name not in devices:
+        raise UsageError(f"Unknown device '{name}', expected one of: {', '.join(devices)}")
+    return devices[name]
 
 
 def check_valid_str(value, source: str = None) -> None:
     if (type(value) is not str) or (len(value) == 0):
-        raise RuntimeError(f"Invalid value read from '{source}'")
+        raise DeviceError(f"Invalid value read from '{source}'")
+
+
+def valid_brightness(value: str) -> bool:
+    return value.isdigit() and 0 <= int(value) <= 255
+
+
+def valid_brightness_setting(value: str) -> bool:
+    # what may be asked for: a raw brightness, or a percentage of max_brightness
+    if value.endswith("%"):
+        return value[:-1].isdigit() and int(value[:-1]) <= 100
+    return valid_brightness(value)
+
+
+def parse_color(value: str) -> str:
+    value = value.strip()
+    name = re.sub(r"[\s_-]", "", value.lower())
+    if name in COLOR_NAMES:
+        return COLOR_NAMES[name]
+    return value.lstrip("#").upper()
+
+
+def valid_color(value: str) -> bool:
+    return re.fullmatch(r"[0-9A-Fa-f]{6}", value) is not None
+
+
+def allowed_color(configuration: dict, color: str) -> bool:
+    # any valid color, unless 'color.allowed' narrows them down
+    allowed = configuration["color"].get("allowed")
+    return valid_color(color) and (not allowed or color.upper() in allowed)
+
+
+def enforced_color(configuration: dict, color: str) -> str:
+    # an allowed color for a valid one: itself, or with 'color.enforce' "nearest" the closest allowed one by RGB
+    # distance; None when it has to be refused
+    if allowed_color(configuration, color):
+        return color
+    if not valid_color(color) or configuration["color"].get("enforce", "reject") != "nearest":
+        return None
 
+    def distance(other):
+        return sum((int(color[i:i + 2], 16) - int(other[i:i + 2], 16)) ** 2 for i in (0, 2, 4))
 
-def read_state(configuration: dict) -> dict:
-    default_brightness = configuration["brightness"]["default"]
+    return min(configuration["color"]["allowed"], key=distance)
+
+
+def checked_color(configuration: dict, color: str) -> str:
+    # the color to write in place of one asked for, refusing it outright if it isn't allowed
+    enforced = enforced_color(configuration, color)
+    if enforced is None:
+        allowed = ", ".join(configuration["color"]["allowed"])
+        raise UsageError(f"Color '{color}' is not allowed by the configuration, expected one of: {allowed}")
+    if enforced != color:
+        logger.info(f"Using the nearest allowed color {enforced} for {color}")
+    return enforced
+
+
+def color_palette(configuration: dict) -> list:
+    # what 'color next' and 'demo' go through: the palette, or else the allowed colors, or else the built-in one
+    return configuration["color"].get("palette") or configuration["color"].get("allowed") or DEFAULT_PALETTE
+
+
+def read_state(configuration: dict, use_history: bool = True) -> dict:
+    # 'use_history' allows falling back to the latest state in the history when the state file is corrupted
+    default_brightness = brightness_default(configuration)
     default_color = configuration["color"]["default"]
     state_path = configuration["state_path"]
     # noinspection PyBroadException
     try:
-        with open(state_path, "rt") as state_file:
-            state = json.load(state_file)
-            if not 0 <= int(state["brightness"]) <= 255:
-                state["brightness"] = default_brightness
-            if not re.fullmatch(r"^(00|FF){3}$", state["color"]):
-                state["brightness"] = default_color
-    except Exception:
+        with state_lock(configuration), open(state_path, "rt") as state_file:
+            document = json.load(state_file)
+        if type(document) is not dict:
+            raise ValueError("not a state object")
+    except StatemgrError:
+        raise
+    except ValueError as e:
+        # a file that isn't even JSON was damaged rather than edited
+        logger.warning(f"The state in '{state_path}' is corrupted ({e})")
+        return fallback_state(configuration, use_history)
+    except Exception as e:
+        document = None
+        logger.info(f"No usable state in '{state_path}' ({e}), using defaults")
+    if document is not None and checksum_status(document) == "mismatch":
+        try:
+            sensible = state_values(device_state(configuration, migrate_state(copy.deepcopy(document)))) is not None
+        except ValueError:
+            sensible = False
+        if not sensible:
+            # a changed file that no longer makes sense was most likely damaged
+            logger.warning(f"The state in '{state_path}' is corrupted (its checksum does not match)")
+            return fallback_state(configuration, use_history)
+        logger.info(f"The state in '{state_path}' was edited since it was saved, using it as it is")
+    # noinspection PyBroadException
+    try:
+        if document is None:
+            raise ValueError("nothing saved")
+        state = device_state(configuration, migrate_state(document))
+        if type(state) is not dict:
+            raise ValueError(f"nothing saved for {configuration['device']}")
+        state.pop("checksum", None)
+        # each value falls back to its default on its own, so a state missing one (or holding one this version
+        # doesn't accept) still restores the rest
+        if type(state.get("brightness")) is not str or not valid_brightness(state["brightness"]):
+            logger.info(f"Saved brightness {state.get('brightness')!r} is not valid, using the default")
+            state["brightness"] = default_brightness
+        rescale_brightness(configuration, state)
+        # 'enabled' has the last word when it disagrees with the brightness, as after an edit turning it on or off
+        last_brightness = state.get("last_brightness")
+        if state.get("enabled") is False and state["brightness"] != "0":
+            state["last_brightness"] = state["brightness"]
+            state["brightness"] = "0"
+        elif state.get("enabled") is True and state["brightness"] == "0":
+            on = last_brightness if type(last_brightness) is str and valid_brightness(last_brightness) else None
+            state["brightness"] = on if on is not None and on != "0" else default_brightness
+        color = enforced_color(configuration, state["color"]) if type(state.get("color")) is str else None
+        if color is None:
+            logger.info(f"Saved color {state.get('color')!r} is not valid or not allowed, using the default")
+            state["color"] = default_color
+        elif color != state["color"]:
+            logger.info(f"Saved color {state['color']!r} is not allowed, using the nearest allowed color {color}")
+            state["color"] = color
+    except StatemgrError:
+        raise
+    except Exception as e:
+        if document is not None:
+            logger.info(f"No usable state in '{state_path}' ({e}), using defaults")
         state = {"brightness": default_brightness, "color": default_color}
+    zones = configuration.get("zones", {})
+    if zones:
+        saved = state.get("zones") if type(state.get("zones")) is dict else {}
+        state["zones"] = {
+            zone: enforced_color(configuration, saved[zone]) or settings["default"]
+            if type(saved.get(zone)) is str
+            else settings["default"]
+            for zone, settings in zones.items()
+        }
     return state
 
 
-def write_state(configuration: dict, state: dict):
-    state_path = configuration["state_path"]
-    pathlib.PosixPath(state_path).parent.mkdir(parents=True, exist_ok=True)
-    with open(state_path, "wt") as out_file:
-        json.dump(state, out_file, indent=2)
-        out_file.write("\n")
-    return
+def rescale_brightness(configuration: dict, state: dict):
+    # a state saved out of a different max_brightness, as when a kernel update moves a driver from 0-255 to 0-3,
+    # keeps its proportion of the range; a keyboard that was lit stays lit
+    saved = state.get("max_brightness")
+    current = read_max_brightness(configuration)
+    if type(saved) is not int or saved <= 0 or saved == current:
+        return
+    for key in ("brightness", "last_brightness"):
+        value = state.get(key)
+        if type(value) is not str or not valid_brightness(value):
+            continue
+        scaled = round(int(value) * current / saved)
+        state[key] = str(max(scaled, 1) if int(value) != 0 else 0)
+    logger.info(f"Scaled the saved brightness from a maximum of {saved} to {current}, giving {state['brightness']}")
+    state["max_brightness"] = current
 
 
-def apply_state(configuration: dict, state: dict):
-    brightness_path = configuration["brightness"]["path"]
-    color_path = configuration["color"]["path"]
-    with open(brightness_path, "at") as brightness_file:
-        brightness_file.write(state["brightness"] + "\n")
-    with open(color_path, "at") as color_file:
-        color_file.write(state["color"] + "\n")
+def state_checksum(document: dict) -> str:
+    # over everything else in the document, in a canonical form, so that formatting changes don't count
+    contents = {key: value for key, value in document.items() if key != "checksum"}
+    canonical = json.dumps(contents, sort_keys=True, separators=(",", ":"))
+    return f"sha256:{hashlib.sha256(canonical.encode()).hexdigest()}"
 
 
-def do_pre(configuration: dict) -> None:
-    with open(configuration["brightness"]["path"], "rt") as brightness_file:
-        brightness = brightness_file.readline().strip()
-        check_valid_str(brightness, source=brightness_file.name)
-    with open(configuration["color"]["path"], "rt") as color_file:
-        color = color_file.readline().strip()
-        check_valid_str(color, source=color_file.name)
-    write_state(configuration, {"brightness": brightness, "color": color})
+def checksum_status(document: dict) -> str:
+    # "match", "mismatch", or "none" for a state saved before checksums (or written by something else)
+    if "checksum" not in document:
+        return "none"
+    return "match" if document["checksum"] == state_checksum(document) else "mismatch"
 
 
-def do_post(configuration: dict) -> None:
-    state = read_state(configuration)
-    apply_state(configuration, state)
+def fallback_state(configuration: dict, use_history: bool) -> dict:
+    # in place of a corrupted state: the latest state in the history, or else the defaults
+    latest = history_state_path(configuration["state_path"], 1)
+    if use_history and configuration.get("history", DEFAULT_HISTORY) > 0 and os.path.exists(latest):
+        logger.warning(f"Using the previous state, from '{latest}'")
+        return read_state(dict(configuration, state_path=latest), use_history=False)
+    logger.warning("Using the defaults")
+    return default_state(configuration)
 
 
-def main():
-    parser = argparse.ArgumentParser(
-        description="https://github.com/draeath/s76-kbd-led-statemgr/blob/master/README.md",
+def default_state(configuration: dict) -> dict:
+    state = {
+        "brightness": brightness_default(configuration),
+        "color": configuration["color"]["default"],
+    }
+    if configuration.get("zones"):
+        state["zones"] = {zone: settings["default"] for zone, settings in configuration["zones"].items()}
+    return state
+
+
+def duration_seconds(value) -> int:
+    # a number of seconds, or a string such as "90s", "30m", "12h", "7d" or "2w"; None if it is neither
+    if type(value) is int and value >= 0:
+        return value
+    match = re.fullmatch(r"(\d+)\s*([smhdw]?)", value.strip()) if type(value) is str else None
+    if match is None:
+        return None
+    return int(match.group(1)) * {"": 1, "s": 1, "m": 60, "h": 3600, "d": 86400, "w": 604800}[match.group(2)]
+
+
+def stale_state(configuration: dict) -> bool:
+    # whether the state file is older than 'state_max_age', e.g. saved on a suspend that never resumed
+    if "state_max_age" not in configuration:
+        return False
+    try:
+        age = time.time() - os.stat(configuration["state_path"]).st_mtime
+    except OSError:
+        return False
+    max_age = duration_seconds(configuration["state_max_age"])
+    if age <= max_age:
+        return False
+    logger.warning(
+        f"The saved state in '{configuration['state_path']}' is {datetime.timedelta(seconds=round(age))} old, "
+        f"more than 'state_max_age' ({configuration['state_max_age']}), so restoring the defaults instead"
     )
-    parser.add_argument(
-        "transition",
-        type=str,
-        help="The [pre|post] keyword from systemd-suspend.service",
-    )
-    args = parser.parse_known_args()[0]
-    if args.transition not in ["pre", "post"]:
-        raise ValueError(f"Invalid argument '{args.transition}'")
-    configuration = read_configuration()
-
-    if args.transition == "pre":
-        do_pre(configuration)
-    elif args.transition == "post":
-        do_post(configuration)
+    return True
+
+
+def color_configured(configuration: dict) -> bool:
+    # with 'color.enabled' false, e.g. for a device without a color attribute, only brightness (and zones) are managed
+    return configuration["color"].get("enabled", True) is not False
+
+
+def color_enabled(configuration: dict) -> bool:
+    # as color_configured, but also false for a device that turns out to have no color attribute, such as a model
+    # with a white-only backlight given a configuration written for another, so that its brightness is still saved
+    # and restored rather than the whole command failing
+    if not color_configured(configuration):
+        return False
+    if BACKENDS[configuration.get("backend", "sysfs")].uses_paths:
+        path = configuration["color"]["path"]
+        # a missing brightness attribute means a missing device, which fails as it always has
+        if not os.path.exists(path) and os.path.exists(configuration["brightness"]["path"]):
+            if path not in MISSING_COLORS:
+                MISSING_COLORS.add(path)
+                logger.warning(
+                    f"The color attribute '{path}' does not exist, so only brightness is managed; set "
+                    f"'color.enabled' to false if the device has no color"
+                )
+            return False
+    return True
+
+
+def zone_paths(configuration: dict) -> dict:
+    # zone attributes sit beside brightness, as color_left, color_center and so on, unless given a path
+    if not configuration.get("zones"):
+        return {}
+    directory = pathlib.PosixPath(configuration["brightness"]["path"]).parent
+    return {
+        zone: settings.get("path", str(directory / f"color_{zone}"))
+        for zone, settings in configuration.get("zones", {}).items()
+    }
+
+
+def history_state_path(state_path: str, entry: int) -> str:
+    # replaced states live beside the state file, e.g. state.json -> state.history-1.json, the latest
+    path = pathlib.PosixPath(state_path)
+    return str(path.with_name(f"{path.stem}.history-{entry}{path.suffix}"))
+
+
+def rotate_history(configuration: dict, state: dict):
+    # move the saved state into the history before it is replaced, dropping the oldest once there are 'history'
+    keep = configuration.get("history", DEFAULT_HISTORY)
+    state_path = configuration["state_path"]
+    saved = load_state_file(configuration)
+    if keep == 0:
+        prune_history(state_path, keep)
+    if keep == 0 or saved is None or state_path in ROTATED_HISTORY:
+        # once is enough when a run saves several devices into the same file
+        return
+    if state_values(saved) == state_values(state) and saved.get("zones") == state.get("zones"):
+        # e.g. a suspend saving what the last one did; a history of identical entries recovers nothing
+        return
+    for entry in range(keep - 1, 0, -1):
+        with contextlib.suppress(FileNotFoundError):
+            os.replace(history_state_path(state_path, entry), history_state_path(state_path, entry + 1))
+    prune_history(state_path, keep)
+    latest = history_state_path(state_path, 1)
+    # a hard link keeps the old file as it is once the new state is renamed over it
+    with contextlib.suppress(FileNotFoundError):
+        os.remove(latest)
+    try:
+        os.link(state_path, latest)
+    except OSError:
+        shutil.copy2(state_path, latest)
+    ROTATED_HISTORY.add(state_path)
+
+
+def prune_history(state_path: str, keep: int):
+    # every entry beyond 'history', not just the one the rotation pushed out, so that after lowering it the older
+    # entries are not left for 'restore --from' to find
+    path = pathlib.PosixPath(state_path)
+    entry_name = re.compile(rf"{re.escape(path.stem)}\.history-(\d+){re.escape(path.suffix)}")
+    for candidate in path.parent.glob(f"{glob.escape(path.stem)}.history-*{glob.escape(path.suffix)}"):
+        found = entry_name.fullmatch(candidate.name)
+        if found and int(found.group(1)) > keep:
+            with contextlib.suppress(FileNotFoundError):
+                candidate.unlink()
+
+
+def rotate_backups(configuration: dict):
+    # plain copies of the file about to be overwritten, as state.json.1 (the latest) up to 'backups', for recovering
+    # by hand. Unlike the history, every overwrite counts.
+    keep = configuration.get("backups", 0)
+    state_path = configuration["state_path"]
+    if keep == 0 or state_path in BACKED_UP or not os.path.exists(state_path):
+        return
+    for number in range(keep - 1, 0, -1):
+        with contextlib.suppress(FileNotFoundError):
+            os.replace(f"{state_path}.{number}", f"{state_path}.{number + 1}")
+    shutil.copy2(state_path, f"{state_path}.1")
+    BACKED_UP.add(state_path)
+
+
+def slot_state_path(state_path: str, slot: int) -> str:
+    # slots live beside the main state file, e.g. state.json -> state.slot-2.json
+    path = pathlib.PosixPath(state_path)
+    return str(path.with_name(f"{path.stem}.slot-{slot}{path.suffix}"))
+
+
+def snapshot_state_path(state_path: str, name: str) -> str:
+    # like slots, snapshots live beside the state file, e.g. state.json -> state.snapshot-evening.json
+    path = pathlib.PosixPath(state_path)
+    return str(path.with_name(f"{path.stem}.snapshot-{name}{path.suffix}"))
+
+
+def lock_path(configuration: dict) -> pathlib.PosixPath:
+    # a separate file, as the state file itself is replaced on every write
+    state_path = pathlib.PosixPath(configuration["state_path"])
+    return state_path.with_name(f".{state_path.name}.lock")
+
+
+@contextlib.contextmanager
+def state_lock(configuration: dict, exclusive: bool = False):
+    # an advisory flock() around state file access, shared for reading and exclusive for writing, so that
+    # overlapping runs (two sleep hooks, or 'set' during 'save') don't interleave. Re-entrant within a run.
+    path = lock_path(configuration)
+    held = STATE_LOCKS.get(path)
+    if held is not None and (held["exclusive"] or not exclusive):
+        held["depth"] += 1
+        try:
+            yield
+        finally:
+            held["depth"] -= 1
+        return
+    if held is not None:
+        # upgrading a shared lock to an exclusive one
+        acquire_lock(path, held["descriptor"], exclusive=True)
+        held["exclusive"] = True
+        yield
+        return
+    try:
+        if exclusive:
+            make_state_directory(configuration, path)
+            created = not path.exists()
+            descriptor = os.open(path, os.O_RDWR | os.O_CREAT, 0o666)
+            if created:
+                secure_state_path(configuration, path, "state_mode")
+        else:
+            descriptor = os.open(path, os.O_RDONLY)
+    except OSError as e:
+        # readers without a lock file (nothing saved yet, or a read-only state directory) go without
+        if exclusive:
+            raise os_error(StateError, f"Unable to open lock file '{path}'", e)
+        logger.debug(f"Not locking '{path}' ({e.strerror})")
+        yield
+        return
+    STATE_LOCKS[path] = {"descriptor": descriptor, "exclusive": exclusive, "depth": 1}
+    try:
+        acquire_lock(path, descriptor, exclusive)
+        yield
+    finally:
+        del STATE_LOCKS[path]
+        os.close(descriptor)
+
+
+def acquire_lock(path: pathlib.PosixPath, descriptor: int, exclusive: bool):
+    operation = fcntl.LOCK_EX if exclusive else fcntl.LOCK_SH
+    deadline = time.monotonic() + LOCK_TIMEOUT
+    while True:
+        try:
+            fcntl.flock(descriptor, operation | fcntl.LOCK_NB)
+            return
+        except BlockingIOError:
+            if time.monotonic() >= deadline:
+                raise StateError(f"Timed out after {LOCK_TIMEOUT} s waiting for another run to release '{path}'")
+            logger.debug(f"Waiting for another run to release '{path}'")
+            time.sleep(0.1)
+
+
+def device_state(configuration: dict, document):
+    # a device's own state in the state file: with several devices configured, its entry under 'devices'. The
+    # first device's may still be the whole file, from before there were several.
+    key = configuration.get("state_key")
+    if key is None or type(document) is not dict:
+        return document
+    devices = document.get("devices")
+    if type(devices) is dict and key in devices:
+        return dict(devices[key], version=document["version"]) if type(devices[key]) is dict else None
+    if configuration.get("single_state") and "brightness" in document:
+        return {key: value for key, value in document.items() if key != "devices"}
+    return None
+
+
+def shared_state_document(configuration: dict, state: dict) -> dict:
+    # the whole state file with this device's state put in under 'devices', keeping the other devices'
+    document = load_state_file(configuration, whole=True)
+    document = document if type(document) is dict else {}
+    if configuration.get("single_state"):
+        # the first device's state from before there were several gives way to its entry
+        document = {key: value for key, value in document.items() if key == "devices"}
+    devices = document.get("devices") if type(document.get("devices")) is dict else {}
+    return {
+        "version": STATE_VERSION,
+        **{key: value for key, value in document.items() if key not in ("version", "checksum", "devices")},
+        "devices": dict(devices, **{configuration["state_key"]: state}),
+    }
+
+
+def load_state_file(configuration: dict, whole: bool = False):
+    # the device's state, or with 'whole' the entire file when it holds several devices'
+    # noinspection PyBroadException
+    try:
+        with state_lock(configuration), open(configuration["state_path"], "rt") as state_file:
+            document = migrate_state(json.load(state_file))
+        return document if whole else device_state(configuration, document)
+    except StatemgrError:
+        raise
+    except Exception:
+        return None
+
+
+def read_state_document(path: str) -> dict:
+    # a state document supplied by the user, as opposed to the state file, which is allowed to be stale or broken
+    source = "stdin" if path == "-" else f"'{path}'"
+    try:
+        if path == "-":
+            document = migrate_state(json.load(sys.stdin))
+        else:
+            with open(path, "rt") as document_file:
+                document = migrate_state(json.load(document_file))
+    except OSError as e:
+        raise os_error(StateError, f"Unable to read {source}", e)
+    except ValueError as e:
+        raise StateError(f"Unable to parse {source}: {e}")
+    if type(document) is not dict:
+        raise StateError(f"{source} does not contain a state object")
+    brightness = document.get("brightness")
+    color = parse_color(document["color"]) if type(document.get("color")) is str else None
+    if type(brightness) is not str or not valid_brightness(brightness):
+        raise StateError(f"{source} has an invalid brightness {brightness!r}")
+    if color is None or not valid_color(color):
+        raise StateError(f"{source} has an invalid color {document.get('color')!r}")
+    state = {"brightness": brightness, "color": color}
+    zones = document.get("zones", {})
+    if type(zones) is not dict:
+        raise StateError(f"{source} has zones that are not an object")
+    for zone, zone_color in zones.items():
+        if type(zone_color) is not str or not valid_color(parse_color(zone_color)):
+            raise StateError(f"{source} has an invalid {zone} zone color {zone_color!r}")
+        state.setdefault("zones", {})[zone] = parse_color(zone_color)
+    last_brightness = document.get("last_brightness")
+    if type(last_brightness) is str and valid_brightness(last_brightness) and int(last_brightness) != 0:
+        state["last_brightness"] = last_brightness
+    return state
+
+
+def state_values(document) -> dict:
+    # the brightness and color of a saved state, or None when they are missing or invalid
+    if type(document) is not dict:
+        return None
+    brightness = document.get("brightness")
+    color = document.get("color")
+    if type(brightness) is not str or not valid_brightness(brightness):
+        return None
+    if type(color) is not str or not valid_color(color):
+        return None
+    return {"brightness": brightness, "color": color}
+
+
+def saved_last_brightness(configuration: dict):
+    last_brightness = (load_state_file(configuration) or {}).get("last_brightness")
+    if type(last_brightness) is str and valid_brightness(last_brightness) and int(last_brightness) != 0:
+        return last_brightness
+    return None
+
+
+def state_id(value, lookup) -> int:
+    # a user or group id from a name, or from the id itself
+    if type(value) is int and value >= 0:
+        return value
+    if type(value) is not str:
+        raise TypeError(value)
+    return int(value) if value.isdigit() else lookup(value)[2]
+
+
+def secure_state_path(configuration: dict, path: pathlib.PosixPath, mode_key: str):
+    # apply the configured mode, owner and group to a newly created state file or directory
+    uid = state_id(configuration["state_owner"], pwd.getpwnam) if "state_owner" in configuration else -1
+    gid = state_id(configuration["state_group"], grp.getgrnam) if "state_group" in configuration else -1
+    if (uid, gid) != (-1, -1):
+        os.chown(path, uid, gid)
+    if mode_key in configuration:
+        path.chmod(int(configuration[mode_key], 8))
+
+
+def fsync_directory(path: pathlib.PosixPath):
+    # entries added to, renamed in or removed from a directory are only durable once the directory itself is
+    directory = os.open(path, os.O_RDONLY | os.O_DIRECTORY)
+    try:
+        os.fsync(directory)
+    finally:
+        os.close(directory)
+
+
+def make_state_directory(configuration: dict, path: pathlib.PosixPath):
+    # the directory for a state file, creating any missing levels with the configured permissions
+    missing = [parent for parent in path.parents if not parent.exists()]
+    path.parent.mkdir(parents=True, exist_ok=True)
+    for directory in reversed(missing):
+        secure_state_path(configuration, directory, "state_directory_mode")
+        logger.debug(f"Created '{directory}'")
+    if missing:
+        # otherwise a state written straight after could survive a power loss that the directory holding it doesn't
+        fsync_directory(missing[-1].parent)
+        for directory in missing[1:]:
+            fsync_directory(directory)
+
+
+def write_state_file(configuration: dict, path: pathlib.PosixPath, document: dict):
+    # used for everything kept beside the state file, so that it all shares the configured permissions
+    make_state_directory(configuration, path)
+    # written to a temporary file that is then renamed over the old one, so a crash or power loss part way
+    # leaves either the old state or the new one, never a truncated file
+    descriptor, temporary = tempfile.mkstemp(prefix=f".{path.name}.", suffix=".tmp", dir=path.parent)
+    temporary = pathlib.PosixPath(temporary)
+    try:
+        # the permissions come first, so that the fsync below makes them durable along with the contents
+        try:
+            existing = path.stat()
+        except FileNotFoundError:
+            # mkstemp makes the file private, whereas a new state file normally follows the umask
+            umask = os.umask(0)
+            os.umask(umask)
+            temporary.chmod(0o666 & ~umask)
+            secure_state_path(configuration, temporary, "state_mode")
+        else:
+            temporary.chmod(stat.S_IMODE(existing.st_mode))
+            if (existing.st_uid, existing.st_gid) != (os.geteuid(), os.getegid()):
+                os.chown(temporary, existing.st_uid, existing.st_gid)
+        with os.fdopen(descriptor, "wt") as out_file:
+            json.dump(document, out_file, indent=2)
+            out_file.write("\n")
+            out_file.flush()
+            os.fsync(out_file.fileno())
+        os.replace(temporary, path)
+    except BaseException:
+        temporary.unlink(missing_ok=True)
+        raise
+    # the rename itself is only durable once the directory is
+    fsync_directory(path.parent)
+
+
+def unchanged_state(configuration: dict, state: dict) -> bool:
+    # whether the state file already holds this state, intact and in the current format, apart from when and by what
+    # it was saved
+    document = load_state_file(configuration, whole=True)
+    if type(document) is not dict or checksum_status(document) != "match":
+        return False
+    if configuration.get("state_key") is not None and configuration["state_key"] not in document.get("devices", {}):
+        # still the whole file, from when only the first device was configured, so it is to be moved in
+        return False
+    ignored = ("version", "checksum", "saved_at", "saved_by", "hostname")
+    saved = device_state(configuration, document)
+    return {key: value for key, value in saved.items() if key not in ignored} == {
+        key: value for key, value in state.items() if key not in ignored
+    }
+
+
+def state_metadata(saved_by: str) -> dict:
+    # when, by which command and on which machine a state was saved, for working out long after what produced it
+    return {
+        "saved_at": datetime.datetime.now().astimezone().isoformat(timespec="seconds"),
+        "saved_by": saved_by,
+        "hostname": os.uname().nodename,
+    }
+
+
+def state_directory_writable(path: str) -> bool:
+    # whether the state file's directory, or the nearest one that exists to create it in, can be written
+    for directory in pathlib.PosixPath(path).parents:
+        if directory.exists():
+            return os.access(directory, os.W_OK)
+    return False
+
+
+def freshest_state_path(configuration: dict) -> str:
+    # the state file, or the runtime one when something saved there more recently, for 'post' after a 'pre' that
+    # had to fall back to it
+    runtime_state_path = configuration.get("runtime_state_path", DEFAULT_RUNTIME_STATE_PATH)
+    paths = [path for path in (configuration["state_path"], runtime_state_path) if path and os.path.exists(path)]
+    if not paths:
+        return configuration["state_path"]
+    freshest = max(paths, key=lambda path: os.stat(path).st_mtime)
+    if freshest != configuration["state_path"]:
+        logger.info(f"Using the state in '{freshest}', saved more recently than '{configuration['state_path']}'")
+    return freshest
+
+
+def write_state(configuration: dict, state: dict, saved_by: str, dry_run: bool = False):
+    # 'saved_by' is the command saving the state: 'pre' for the sleep hook, 'set', and so on
+    runtime_state_path = configuration.get("runtime_state_path", DEFAULT_RUNTIME_STATE_PATH)
+    if runtime_state_path and not state_directory_writable(configuration["state_path"]):
+        # e.g. a read-only or not yet mounted /var on an immutable distribution, or late in shutdown
+        logger.warning(
+            f"Unable to write to the directory of '{configuration['state_path']}', saving the state to "
+            f"'{runtime_state_path}' instead"
+        )
+        configuration = dict(configuration, state_path=runtime_state_path, runtime_state_path=None)
+    state_path = configuration["state_path"]
+    # held across reading the state being replaced and writing the new one, so concurrent runs take turns
+    with state_lock(configuration, exclusive=not dry_run):
+        # remember the last non-zero brightness so that toggling back on survives reboots, and whether it was on
+        state["enabled"] = int(state["brightness"]) != 0
+        # and out of how many levels, so that a driver changing its range can be made up for when restoring
+        state["max_brightness"] = read_max_brightness(configuration)
+        if int(state["brightness"]) != 0:
+            state["last_brightness"] = state["brightness"]
+        elif "last_brightness" not in state:
+            previous = saved_last_brightness(configuration)
+            if previous is not None:
+                state["last_brightness"] = previous
+        # keep whatever is being replaced, so that 'undo' can go back to it
+        if "previous" not in state:
+            saved = load_state_file(configuration)
+            replaced = state_values(saved)
+            if replaced is not None and replaced != state_values(state):
+                state["previous"] = replaced
+            elif state_values((saved or {}).get("previous")) is not None:
+                state["previous"] = state_values(saved["previous"])
+        if unchanged_state(configuration, state):
+            # e.g. suspending many times a day without touching the keyboard; only the time is brought up to date,
+            # so that 'state_max_age' counts from this save
+            if dry_run:
+                print(f"Would leave the state in '{state_path}' as it is, as nothing changed")
+                return
+            try:
+                os.utime(state_path)
+            except OSError as e:
+                raise os_error(StateError, f"Unable to update state '{state_path}'", e)
+            logger.info(f"The state in '{state_path}' is unchanged, not rewriting it")
+            return
+        state.update(state_metadata(saved_by))
+        if dry_run:
+            print(f"Would write state to '{state_path}': {json.dumps({'version': STATE_VERSION, **state})}")
+            return
+        try:
+            rotate_history(configuration, state)
+            rotate_backups(configuration)
+            saved = {key: value for key, value in state.items() if key not in ("version", "checksum")}
+            if configuration.get("state_key") is None:
+                document = {"version": STATE_VERSION, **saved}
+            else:
+                document = shared_state_document(configuration, saved)
+            document["checksum"] = state_checksum(document)
+            write_state_file(configuration, pathlib.PosixPath(state_path), document)
+        except OSError as e:
+            raise os_error(StateError, f"Unable to write state '{state_path}'", e)
+        logger.info(f"Saved brightness '{state['brightness']}' and color '{state['color']}' to '{state_path}'")
+
+
+def apply_state(configuration: dict, state: dict, dry_run: bool = False, parts: tuple = STATE_PARTS):
+    # 'parts' is which of brightness, color and zones to write, for the per-transition switches
+    if int(state["brightness"]) > brightness_limit(configuration):
+        # clamp the state as well, so what gets saved matches what the keyboard shows; without a configured maximum
+        # this is the device's own, which a brightness meant for another device (or the default) can exceed
+        logger.info(f"Limiting brightness {state['brightness']} to the maximum of {brightness_limit(configuration)}")
+        state["brightness"] = str(brightness_limit(configuration))
+    paths = zone_paths(configuration)
+    zones = {zone: color for zone, color in state.get("zones", {}).items() if zone in paths}
+    # as with the brightness limit, the state changes too, so what gets saved matches the keyboard
+    if color_enabled(configuration):
+        state["color"] = checked_color(configuration, state["color"])
+    for zone, color in zones.items():
+        zones[zone] = state["zones"][zone] = checked_color(configuration, color)
+    device_backend(configuration).apply(configuration, state, dry_run, parts)
+
+
+def read_attribute(path: str) -> str:
+    try:
+        with open(path, "rt") as attribute_file:
+            value = attribute_file.readline().strip()
+    except OSError as e:
+        raise os_error(DeviceError, f"Unable to read '{path}'", e)
+    check_valid_str(value, source=path)
+    return value
+
+
+def read_hardware(configuration: dict) -> dict:
+    return device_backend(configuration).read(configuration)
+
+
+def hardware_color(configuration: dict, value: str) -> str:
+    # a color attribute's value as RRGGBB, going by 'color.format'; anything unexpected is left as it is, for
+    # validation to refuse
+    color_format = configuration["color"].get("format", "hex")
+    if color_format == "hex" and valid_color(value):
+        # upper case, as saved and configured colors are, so that comparing them doesn't find lowercase drift
+        return value.upper()
+    if color_format == "0x" and re.fullmatch(r"0[xX][0-9A-Fa-f]{1,6}", value):
+        return value[2:].zfill(6).upper()
+    if color_format == "rgb" and re.fullmatch(r"\d{1,3} \d{1,3} \d{1,3}", value):
+        channels = [int(channel) for channel in value.split()]
+        if all(channel <= 255 for channel in channels):
+            return "".join(f"{channel:02X}" for channel in channels)
+    return value
+
+
+def sysfs_color(configuration: dict, color: str) -> str:
+    # RRGGBB as the color attributes take it, going by 'color.format'
+    color_format = configuration["color"].get("format", "hex")
+    if color_format == "0x":
+        return f"0x{color.lower()}"
+    if color_format == "rgb":
+        return " ".join(str(int(color[index:index + 2], 16)) for index in (0, 2, 4))
+    return color
+
+
+class LedBackend:
+    # how a device's keyboard backlight is read and written, chosen by its 'backend' setting; everything else only
+    # goes through these, so that new hardware needs a backend rather than changes to saving and restoring
+    name = None
+    # whether brightness.path and color.path locate the device, and whether zones are supported
+    uses_paths = False
+    supports_zones = False
+
+    def read(self, configuration: dict) -> dict:
+        # the current brightness and color (and any zones), as the state holds them
+        raise NotImplementedError
+
+    def apply(self, configuration: dict, state: dict, dry_run: bool, parts: tuple):
+        # writes the 'parts' of an already checked state
+        raise NotImplementedError
+
+    def max_brightness(self, configuration: dict) -> int:
+        return 255
+
+    def paths(self, configuration: dict) -> dict:
+        # where the brightness and color are read and written, for 'status'
+        raise NotImplementedError
+
+    def writable(self, configuration: dict) -> bool:
+        # whether commands run without root may change the device
+        return True
+
+    def check(self, configuration: dict) -> list:
+        # (level, message, hint) findings for 'doctor'
+        return []
+
+
+class SysfsBackend(LedBackend):
+    name = "sysfs"
+    uses_paths = True
+    supports_zones = True
+
+    def read(self, configuration: dict) -> dict:
+        state = {
+            "brightness": read_attribute(configuration["brightness"]["path"]),
+            # the default stands in for the color when it is not managed, as much expects a state to have one
+            "color": hardware_color(configuration, read_attribute(configuration["color"]["path"]))
+            if color_enabled(configuration)
+            else configuration["color"]["default"],
+        }
+        paths = zone_paths(configuration)
+        if paths:
+            state["zones"] = {zone: hardware_color(configuration, read_attribute(path)) for zone, path in paths.items()}
+        return state
+
+    def apply(self, configuration: dict, state: dict, dry_run: bool, parts: tuple):
+        paths = zone_paths(configuration)
+        zones = {zone: color for zone, color in state.get("zones", {}).items() if zone in paths}
+
+        def attribute_path(attribute: str) -> str:
+            # looked up again for each attempt, as a retry may have found the device somewhere new
+            if attribute in ("brightness", "color"):
+                return configuration[attribute]["path"]
+            return zone_paths(configuration)[attribute]
+
+        # (label, attribute, value), the attribute being brightness, color or a zone
+        writes = []
+        if "brightness" in parts:
+            writes.append(("brightness", "brightness", state["brightness"]))
+        if "color" in parts and color_enabled(configuration):
+            writes.append(("color", "color", sysfs_color(configuration, state["color"])))
+        if "zones" in parts:
+            writes += [(f"{zone} zone color", zone, sysfs_color(configuration, color)) for zone, color in zones.items()]
+        if dry_run:
+            for label, attribute, value in writes:
+                print(f"Would write {label} '{value}' to '{attribute_path(attribute)}'")
+            return
+        retries = configuration.get("retries", DEFAULT_RETRIES)
+        interval = configuration.get("retry_interval_ms", DEFAULT_RETRY_INTERVAL_MS)
+        for label, attribute, value in writes:
+            for attempt in range(retries + 1):
+                path = attribute_path(attribute)
+                try:
+                    self.write_attribute(path, value)
+                    break
+                except OSError as e:
+                    if e.errno not in RETRY_ERRORS or attempt == retries:
+                        raise os_error(DeviceError, f"Unable to write '{path}'", e)
+                    delay = min(interval * 2 ** attempt, max(interval, MAX_RETRY_INTERVAL_MS))
+                    logger.warning(f"Unable to write '{path}' ({e.strerror}), retrying in {delay} ms")
+                    time.sleep(delay / 1000)
+                    # a glob pattern, list of candidates or 'match' that found nothing may find it now
+                    locate_device(configuration)
+        logger.info(f"Applied {', '.join(f'{label} {value!r}' for label, attribute, value in writes)}")
+
+    def write_attribute(self, path: str, value: str):
+        # sysfs takes each write as the attribute's new value, whatever the file position
+        with open(path, "at") as attribute_file:
+            attribute_file.write(value + "\n")
+
+    def max_brightness(self, configuration: dict) -> int:
+        max_brightness_path = pathlib.PosixPath(configuration["brightness"]["path"]).with_name("max_brightness")
+        try:
+            with open(max_brightness_path, "rt") as max_brightness_file:
+                return int(max_brightness_file.readline().strip())
+        except (OSError, ValueError) as e:
+            logger.debug(f"Unable to read '{max_brightness_path}' ({e}), assuming 255")
+            return 255
+
+    def paths(self, configuration: dict) -> dict:
+        return {
+            "brightness": configuration["brightness"]["path"],
+            "color": configuration["color"]["path"] if color_enabled(configuration) else None,
+        }
+
+    def writable(self, configuration: dict) -> bool:
+        return os.access(configuration["brightness"]["path"], os.W_OK)
+
+    def check(self, configuration: dict) -> list:
+        findings = []
+        for key in ("brightness", "color") if color_configured(configuration) else ("brightness",):
+            path = pathlib.PosixPath(configuration[key]["path"])
+            if key == "color" and not path.exists() and os.path.exists(configuration["brightness"]["path"]):
+                message = f"Configured color attribute {path} does not exist, so only brightness is managed"
+                findings.append(("warn", message, "Set 'color.enabled' to false if the device has no color"))
+            elif not path.exists():
+                hint = f"Set '{key}.path' to one of the devices listed above"
+                findings.append(("fail", f"Configured {key} attribute {path} does not exist", hint))
+            elif not os.access(path, os.R_OK | os.W_OK):
+                message = f"Configured {key} attribute {path} is not readable and writable"
+                findings.append(("fail", message, "Run as root"))
+            else:
+                findings.append(("ok", f"Configured {key} attribute {path} is readable and writable", None))
+        return findings
+
+
+def openrgb_address(configuration: dict) -> tuple:
+    openrgb = configuration.get("openrgb", {})
+    return openrgb.get("host", OPENRGB_DEFAULT_HOST), openrgb.get("port", OPENRGB_DEFAULT_PORT)
+
+
+def openrgb_send(connection: socket.socket, controller: int, packet: int, data: bytes = b""):
+    connection.sendall(b"ORGB" + struct.pack("<III", controller, packet, len(data)) + data)
+
+
+def openrgb_receive(connection: socket.socket, packet: int) -> bytes:
+    def receive(size: int) -> bytes:
+        data = b""
+        while len(data) < size:
+            chunk = connection.recv(size - len(data))
+            if not chunk:
+                raise DeviceError("The OpenRGB server closed the connection")
+            data += chunk
+        return data
+
+    header = receive(16)
+    magic, (_, received, size) = header[:4], struct.unpack("<III", header[4:])
+    if magic != b"ORGB" or received != packet:
+        raise DeviceError(f"Unexpected reply from the OpenRGB server (packet {received}, expected {packet})")
+    return receive(size)
+
+
+def openrgb_controller_data(data: bytes) -> dict:
+    # the parts of a protocol version 0 controller description that matter here: its type, name and how many LEDs
+    # it has (the number of colors it takes)
+    offset = 4
+
+    def take(layout: str) -> tuple:
+        nonlocal offset
+        values = struct.unpack_from(layout, data, offset)
+        offset += struct.calcsize(layout)
+        return values
+
+    def take_string() -> str:
+        nonlocal offset
+        (length,) = take("<H")
+        offset += length
+        return data[offset - length:offset].rstrip(b"\0").decode("utf-8", "replace")
+
+    (controller_type,) = take("<i")
+    name = take_string()
+    for _ in ("description", "version", "serial", "location"):
+        take_string()
+    (modes,) = take("<H")
+    take("<i")
+    for _ in range(modes):
+        take_string()
+        take("<iIIIIIIII")
+        (colors,) = take("<H")
+        offset += 4 * colors
+    (zones,) = take("<H")
+    for _ in range(zones):
+        take_string()
+        take("<iIII")
+        (matrix_size,) = take("<H")
+        offset += matrix_size
+    (leds,) = take("<H")
+    for _ in range(leds):
+        take_string()
+        take("<I")
+    (colors,) = take("<H")
+    colors = list(struct.iter_unpack("<BBBx", data[offset:offset + 4 * colors]))
+    return {"type": controller_type, "name": name, "colors": colors}
+
+
+def openrgb_controller(configuration: dict):
+    # a connection to the OpenRGB server, with the index and description of the configured controller: the one
+    # named by 'openrgb.controller', or the first keyboard
+    host, port = openrgb_address(configuration)
+    wanted = configuration.get("openrgb", {}).get("controller")
+    try:
+        connection = socket.create_connection((host, port), timeout=OPENRGB_TIMEOUT)
+    except OSError as e:
+        raise os_error(DeviceError, f"Unable to reach the OpenRGB server at {host}:{port}", e)
+    try:
+        openrgb_send(connection, 0, OPENRGB_SET_CLIENT_NAME, b"s76-kbd-led-statemgr\0")
+        openrgb_send(connection, 0, OPENRGB_REQUEST_CONTROLLER_COUNT)
+        (count,) = struct.unpack("<I", openrgb_receive(connection, OPENRGB_REQUEST_CONTROLLER_COUNT)[:4])
+        names = []
+        for index in range(count):
+            openrgb_send(connection, index, OPENRGB_REQUEST_CONTROLLER_DATA)
+            controller = openrgb_controller_data(openrgb_receive(connection, OPENRGB_REQUEST_CONTROLLER_DATA))
+            names.append(controller["name"])
+            if wanted:
+                found = controller["name"].lower() == wanted.lower()
+            else:
+                found = controller["type"] == OPENRGB_KEYBOARD_TYPE
+            if found:
+                return connection, index, controller
+    except (OSError, struct.error) as e:
+        connection.close()
+        raise DeviceError(f"Unable to talk to the OpenRGB server at {host}:{port} ({e})")
+    except DeviceError:
+        connection.close()
+        raise
+    connection.close()
+    looked_for = f"controller '{wanted}'" if wanted else "keyboard"
+    raise DeviceError(f"The OpenRGB server at {host}:{port} has no {looked_for} among: {', '.join(names) or 'none'}")
+
+
+class OpenRGBBackend(LedBackend):
+    # OpenRGB has no brightness of its own, so it is kept in the colors: the brightest channel is the brightness,
+    # and the color is what that scales up to
+    name = "openrgb"
+
+    def read(self, configuration: dict) -> dict:
+        connection, index, controller = openrgb_controller(configuration)
+        connection.close()
+        if not controller["colors"]:
+            raise DeviceError(f"OpenRGB controller '{controller['name']}' has no LEDs")
+        red, green, blue = controller["colors"][0]
+        brightness = max(red, green, blue)
+        if brightness == 0:
+            # a keyboard that is off keeps the color last saved
+            saved = state_values(load_state_file(configuration))
+            return {"brightness": "0", "color": saved["color"] if saved else configuration["color"]["default"]}
+        color = "".join(f"{round(channel * 255 / brightness):02X}" for channel in (red, green, blue))
+        return {"brightness": str(brightness), "color": color}
+
+    def apply(self, configuration: dict, state: dict, dry_run: bool, parts: tuple):
+        # brightness and color are written together, as one dimmed color, so either part writes both
+        if "brightness" not in parts and "color" not in parts:
+            return
+        color = state["color"] if color_enabled(configuration) else "FFFFFF"
+        scale = int(state["brightness"]) / 255
+        channels = bytes(round(int(color[index:index + 2], 16) * scale) for index in (0, 2, 4))
+        connection, index, controller = openrgb_controller(configuration)
+        with connection:
+            label = f"OpenRGB controller '{controller['name']}'"
+            if dry_run:
+                print(f"Would set the {len(controller['colors'])} LEDs of {label} to {channels.hex().upper()}")
+                return
+            colors = (channels + b"\0") * len(controller["colors"])
+            data = struct.pack("<H", len(controller["colors"])) + colors
+            try:
+                openrgb_send(connection, index, OPENRGB_SET_CUSTOM_MODE)
+                openrgb_send(connection, index, OPENRGB_UPDATE_LEDS, struct.pack("<I", len(data) + 4) + data)
+            except OSError as e:
+                raise os_error(DeviceError, f"Unable to update {label}", e)
+        logger.info(f"Applied brightness {state['brightness']!r} and color {color!r} to {label}")
+
+    def paths(self, configuration: dict) -> dict:
+        host, port = openrgb_address(configuration)
+        return dict.fromkeys(("brightness", "color"), f"OpenRGB server at {host}:{port}")
+
+    def check(self, configuration: dict) -> list:
+        try:
+            connection, index, controller = openrgb_controller(configuration)
+            connection.close()
+        except DeviceError as e:
+            hint = "Start the OpenRGB server ('openrgb --server'), or check 'openrgb.controller'"
+            return [("fail", str(e), hint)]
+        return [("ok", f"OpenRGB controller '{controller['name']}' has {len(controller['colors'])} LEDs", None)]
+
+
+def ite8291_path(configuration: dict) -> str:
+    # the hidraw device of the controller: 'ite8291.path', or the first one with a known USB id
+    if "path" in configuration.get("ite8291", {}):
+        return configuration["ite8291"]["path"]
+    for device in sorted(HIDRAW_PATH.glob("hidraw*")) if HIDRAW_PATH.is_dir() else []:
+        try:
+            uevent = (device / "device" / "uevent").read_text()
+        except OSError:
+            continue
+        found = re.search(r"^HID_ID=[0-9A-Fa-f]+:([0-9A-Fa-f]+):([0-9A-Fa-f]+)$", uevent, re.MULTILINE)
+        if found and (int(found.group(1), 16), int(found.group(2), 16)) in ITE8291_IDS:
+            return f"/dev/{device.name}"
+    ids = ", ".join(f"{vendor:04x}:{product:04x}" for vendor, product in ITE8291_IDS)
+    raise DeviceError(f"No ITE 8291 keyboard controller ({ids}) found in {HIDRAW_PATH}")
+
+
+def ite8291_feature(descriptor: int, *command: int, read: bool = False) -> bytes:
+    # sends an 8-byte command as a feature report, then with 'read' fetches the controller's reply; the leading 0
+    # is the report number, as the controller doesn't number them
+    report = bytearray([0, *command] + [0] * (8 - len(command)))
+    fcntl.ioctl(descriptor, (3 << 30) | (len(report) << 16) | (ord("H") << 8) | 0x06, report)  # HIDIOCSFEATURE
+    if not read:
+        return b""
+    reply = bytearray(len(report))
+    fcntl.ioctl(descriptor, (3 << 30) | (len(reply) << 16) | (ord("H") << 8) | 0x07, reply)  # HIDIOCGFEATURE
+    return bytes(reply[1:])
+
+
+class ITE8291Backend(LedBackend):
+    # the controller reports its brightness but not its colors, so the color last saved stands in
+    name = "ite8291"
+
+    def read(self, configuration: dict) -> dict:
+        path = ite8291_path(configuration)
+        try:
+            descriptor = os.open(path, os.O_RDWR)
+            try:
+                brightness = ite8291_feature(descriptor, 0x88, read=True)[5]
+            finally:
+                os.close(descriptor)
+        except OSError as e:
+            raise os_error(DeviceError, f"Unable to read the brightness from '{path}'", e)
+        saved = state_values(load_state_file(configuration))
+        return {"brightness": str(brightness), "color": saved["color"] if saved else configuration["color"]["default"]}
+
+    def apply(self, configuration: dict, state: dict, dry_run: bool, parts: tuple):
+        # in its user mode the controller takes a color for every key, so the whole keyboard gets the one color
+        path = ite8291_path(configuration)
+        color = state["color"] if color_enabled(configuration) and "color" in parts else None
+        if dry_run:
+            applied = [f"brightness '{state['brightness']}'"] + ([f"color '{color}'"] if color else [])
+            print(f"Would write {' and '.join(applied)} to the ITE 8291 controller at '{path}'")
+            return
+        try:
+            descriptor = os.open(path, os.O_RDWR)
+            try:
+                if color is None:
+                    ite8291_feature(descriptor, 0x09, 0x02, int(state["brightness"]))
+                else:
+                    ite8291_feature(descriptor, 0x08, 0x02, 0x33, 0x00, int(state["brightness"]), 0x00, 0x00, 0x00)
+                    red, green, blue = (int(color[index:index + 2], 16) for index in (0, 2, 4))
+                    # each row is its blue, then green, then red channels, one byte per column
+                    row = bytes([0] + [blue] * ITE8291_COLUMNS + [green] * ITE8291_COLUMNS + [red] * ITE8291_COLUMNS)
+                    for index in range(ITE8291_ROWS):
+                        ite8291_feature(descriptor, 0x16, 0x00, index)
+                        os.write(descriptor, row + b"\0")
+            finally:
+                os.close(descriptor)
+        except OSError as e:
+            raise os_error(DeviceError, f"Unable to write to the ITE 8291 controller at '{path}'", e)
+        logger.info(f"Applied brightness {state['brightness']!r}" + (f" and color {color!r}" if color else ""))
+
+    def max_brightness(self, configuration: dict) -> int:
+        return ITE8291_MAX_BRIGHTNESS
+
+    def paths(self, configuration: dict) -> dict:
+        return dict.fromkeys(("brightness", "color"), ite8291_path(configuration))
+
+    def writable(self, configuration: dict) -> bool:
+        return os.access(ite8291_path(configuration), os.W_OK)
+
+    def check(self, configuration: dict) -> list:
+        try:
+            path = ite8291_path(configuration)
+        except DeviceError as e:
+            return [("fail", str(e), "Check 'lsusb' for the controller, or set 'ite8291.path'")]
+        if not os.access(path, os.R_OK | os.W_OK):
+            return [("fail", f"ITE 8291 controller {path} is not readable and writable", "Run as root")]
+        return [("ok", f"ITE 8291 controller {path} is readable and writable", None)]
+
+
+def busctl(*arguments: str) -> str:
+    try:
+        command = ["busctl", "--system", *arguments]
+        result = subprocess.run(command, capture_output=True, text=True, timeout=DBUS_TIMEOUT)
+    except (OSError, subprocess.SubprocessError) as e:
+        raise DeviceError(f"Unable to run busctl: {e}")
+    if result.returncode != 0:
+        raise DeviceError(f"busctl {arguments[0]} failed: {result.stderr.strip() or f'status {result.returncode}'}")
+    return result.stdout
+
+
+def system76_power_object(configuration: dict) -> str:
+    # 'system76_power.object', or the first keyboard system76-power has
+    if "object" in configuration.get("system76_power", {}):
+        return configuration["system76_power"]["object"]
+    for path in busctl("--list", "tree", SYSTEM76_POWER_SERVICE).split():
+        if "/keyboard/" in path.lower():
+            return path
+    raise DeviceError(f"{SYSTEM76_POWER_SERVICE} has no keyboard objects; is system76-power running and up to date?")
+
+
+def system76_power_property(configuration: dict, name: str):
+    path = system76_power_object(configuration)
+    interface = SYSTEM76_POWER_KEYBOARD_INTERFACE
+    output = busctl("--json=short", "get-property", SYSTEM76_POWER_SERVICE, path, interface, name)
+    try:
+        return json.loads(output)["data"]
+    except (ValueError, KeyError, TypeError):
+        raise DeviceError(f"Unexpected reply from busctl for the {name} of '{path}': {output.strip()!r}")
+
+
+class System76PowerBackend(LedBackend):
+    name = "system76-power"
+
+    def read(self, configuration: dict) -> dict:
+        state = {"brightness": str(system76_power_property(configuration, "brightness"))}
+        if color_enabled(configuration):
+            state["color"] = str(system76_power_property(configuration, "color")).lstrip("#").upper()
+        else:
+            state["color"] = configuration["color"]["default"]
+        check_valid_str(state["brightness"], source="system76-power")
+        return state
+
+    def apply(self, configuration: dict, state: dict, dry_run: bool, parts: tuple):
+        path = system76_power_object(configuration)
+        calls = []
+        if "brightness" in parts:
+            calls.append(("brightness", "set_brightness", "i", state["brightness"]))
+        if "color" in parts and color_enabled(configuration):
+            calls.append(("color", "set_color", "s", state["color"]))
+        for label, method, signature, value in calls:
+            if dry_run:
+                print(f"Would set {label} '{value}' through system76-power's '{path}'")
+                continue
+            busctl("call", SYSTEM76_POWER_SERVICE, path, SYSTEM76_POWER_KEYBOARD_INTERFACE, method, signature, value)
+        if not dry_run:
+            logger.info(f"Applied {', '.join(f'{label} {value!r}' for label, method, signature, value in calls)}")
+
+    def max_brightness(self, configuration: dict) -> int:
+        try:
+            return int(system76_power_property(configuration, "max_brightness"))
+        except (DeviceError, ValueError) as e:
+            logger.debug(f"Unable to read system76-power's max_brightness ({e}), assuming 255")
+            return 255
+
+    def paths(self, configuration: dict) -> dict:
+        return dict.fromkeys(("brightness", "color"), f"system76-power {system76_power_object(configuration)}")
+
+    def check(self, configuration: dict) -> list:
+        # system76-power's own policy decides who may call it, so no root check is made
+        try:
+            path = system76_power_object(configuration)
+            brightness = system76_power_property(configuration, "brightness")
+        except DeviceError as e:
+            return [("fail", str(e), "Start system76-power ('systemctl start com.system76.PowerDaemon')")]
+        return [("ok", f"system76-power has keyboard {path}, at brightness {brightness}", None)]
+
+
+class MockBackend(SysfsBackend):
+    # pretend LED devices, as attribute files in a directory of their own (one per device), made with the
+    # configured defaults on first use; for trying out the whole save and restore flow without the hardware
+    name = "mock"
+    uses_paths = False
+
+    def mocked(self, configuration: dict) -> dict:
+        # the configuration with its paths moved to the pretend device, which is made if need be
+        mock = configuration.get("mock", {})
+        directory = pathlib.PosixPath(mock.get("path", DEFAULT_MOCK_PATH), configuration.get("device", "keyboard"))
+        real = ("path", "configured_path", "format")
+        mocked = dict(
+            {key: value for key, value in configuration.items() if key != "match"},
+            brightness={key: value for key, value in configuration["brightness"].items() if key not in real},
+            color={key: value for key, value in configuration["color"].items() if key not in real},
+            zones={
+                zone: {key: value for key, value in settings.items() if key not in real}
+                for zone, settings in configuration.get("zones", {}).items()
+            },
+        )
+        mocked["brightness"]["path"] = str(directory / "brightness")
+        mocked["color"]["path"] = str(directory / "color")
+        files = {
+            "max_brightness": str(mock.get("max_brightness", 255)),
+            "color": configuration["color"]["default"],
+            **{f"color_{zone}": settings["default"] for zone, settings in mocked["zones"].items()},
+        }
+        try:
+            directory.mkdir(parents=True, exist_ok=True)
+            for name, value in files.items():
+                if not (directory / name).exists():
+                    (directory / name).write_text(value + "\n")
+            if not (directory / "brightness").exists():
+                # within mock.max_brightness, as a real device would be
+                seed = min(int(brightness_default(mocked)), brightness_limit(mocked))
+                (directory / "brightness").write_text(f"{seed}\n")
+        except OSError as e:
+            raise os_error(DeviceError, f"Unable to make the mock device in '{directory}'", e)
+        return mocked
+
+    def read(self, configuration: dict) -> dict:
+        return super().read(self.mocked(configuration))
+
+    def apply(self, configuration: dict, state: dict, dry_run: bool, parts: tuple):
+        super().apply(self.mocked(configuration), state, dry_run, parts)
+
+    def write_attribute(self, path: str, value: str):
+        # plain files keep whatever is appended, where read_attribute only reads the first line
+        pathlib.PosixPath(path).write_text(value + "\n")
+
+    def max_brightness(self, configuration: dict) -> int:
+        return configuration.get("mock", {}).get("max_brightness", 255)
+
+    def paths(self, configuration: dict) -> dict:
+        return super().paths(self.mocked(configuration))
+
+    def writable(self, configuration: dict) -> bool:
+        return True
+
+    def check(self, configuration: dict) -> list:
+        directory = pathlib.PosixPath(self.mocked(configuration)["brightness"]["path"]).parent
+        hint = "Drop --backend mock, or 'backend' from the configuration"
+        return [("warn", f"Using the mock device in {directory}, not the keyboard", hint)]
+
+
+BACKENDS = {
+    backend.name: backend
+    for backend in (SysfsBackend(), OpenRGBBackend(), ITE8291Backend(), System76PowerBackend(), MockBackend())
+}
+
+
+def device_backend(configuration: dict) -> LedBackend:
+    return BACKENDS[configuration.get("backend", "sysfs")]
+
+
+def read_max_brightness(configuration: dict) -> int:
+    return device_backend(configuration).max_brightness(configuration)
+
+
+def resolve_brightness(configuration: dict, value: str) -> str:
+    if value.endswith("%"):
+        return str(round(read_max_brightness(configuration) * int(value[:-1]) / 100))
+    return value
+
+
+def brightness_default(configuration: dict) -> str:
+    # the default may be a percentage, so that one configuration suits keyboards with different brightness ranges
+    return resolve_brightness(configuration, configuration["brightness"]["default"])
+
+
+def brightness_step(configuration: dict) -> int:
+    # unless configured, the same proportion of any device's range as 16 is of 255, so that a keyboard with only a
+    # few levels (as on many ASUS models) still steps through each of them
+    if "step" in configuration["brightness"]:
+        return int(configuration["brightness"]["step"])
+    return max(1, round(read_max_brightness(configuration) * 16 / 255))
+
+
+def brightness_limit(configuration: dict) -> int:
+    # the device's max_brightness, lowered by any configured 'brightness.max'
+    limit = min(read_max_brightness(configuration), 255)
+    if "max" in configuration["brightness"]:
+        limit = min(limit, int(resolve_brightness(configuration, configuration["brightness"]["max"])))
+    return limit
+
+
+def run_hook(configuration: dict, name: str, state: dict, dry_run: bool = False):
+    # a configured command, given the state in S76_KBD_LED_* environment variables. A string runs through the
+    # shell, a list runs as it is. Hooks are for notifications and the like, so a failing one only gets a warning.
+    command = configuration.get("hooks", {}).get(name)
+    if command is None:
+        return
+    if dry_run:
+        print(f"Would run the {name} hook: {command if type(command) is str else shlex.join(command)}")
+        return
+    environment = dict(
+        os.environ,
+        S76_KBD_LED_HOOK=name,
+        S76_KBD_LED_DEVICE=configuration["device"],
+        S76_KBD_LED_BRIGHTNESS=str(state["brightness"]),
+        S76_KBD_LED_COLOR=str(state["color"]),
+        S76_KBD_LED_ZONES=" ".join(f"{zone}={color}" for zone, color in state.get("zones", {}).items()),
+        S76_KBD_LED_STATE_PATH=configuration["state_path"],
+    )
+    logger.debug(f"Running the {name} hook")
+    try:
+        # anything the hook prints goes to stderr, keeping stdout for our own output
+        result = subprocess.run(
+            command, shell=type(command) is str, env=environment, stdout=sys.stderr, timeout=HOOK_TIMEOUT
+        )
+    except (OSError, subprocess.SubprocessError) as e:
+        logger.warning(f"Unable to run the {name} hook: {e}")
+        return
+    if result.returncode != 0:
+        logger.warning(f"The {name} hook exited with status {result.returncode}")
+
+
+def transition_parts(configuration: dict, phase: str) -> tuple:
+    # the parts of the state 'pre' saves or 'post' applies, going by e.g. 'pre.save_color' and 'post.apply_zones'
+    verb = "save" if phase == "pre" else "apply"
+    switches = configuration.get(phase, {})
+    return tuple(part for part in STATE_PARTS if switches.get(f"{verb}_{part}", True))
+
+
+def do_pre(configuration: dict, args: argparse.Namespace) -> dict:
+    state = read_hardware(configuration)
+    parts = transition_parts(configuration, "pre")
+    if parts != STATE_PARTS:
+        # whatever isn't saved keeps its saved (or default) value
+        saved = read_state(configuration)
+        for part in set(STATE_PARTS) - set(parts):
+            if part in saved:
+                state[part] = saved[part]
+            else:
+                state.pop(part, None)
+    run_hook(configuration, "before_save", state, dry_run=args.dry_run)
+    write_state(configuration, state, "pre", dry_run=args.dry_run)
+    run_hook(configuration, "after_save", state, dry_run=args.dry_run)
+    return state
+
+
+def power_source(path: pathlib.PosixPath = POWER_SUPPLY_PATH) -> str:
+    # "ac" when any mains adapter is online, "battery" when there is a battery but no adapter online, otherwise None
+    # (e.g. a desktop, or no power_supply class at all)
+    types = []
+    try:
+        supplies = sorted(path.iterdir())
+    except OSError:
+        return None
+    for supply in supplies:
+        try:
+            kind = (supply / "type").read_text().strip()
+            if kind == "Mains" and (supply / "online").read_text().strip() == "1":
+                return "ac"
+        except OSError:
+            continue
+        types.append(kind)
+    return "battery" if "Battery" in types else None
+
+
+def wait_for_device(configuration: dict, timeout_ms: int, dry_run: bool = False):
+    # after resume the EC driver can take a while to register the LED device again; rather than a fixed delay, wait
+    # up to 'timeout_ms' for its brightness attribute, waking for each uevent the kernel sends, and carry on either
+    # way (the write then fails, after its retries, as it would have)
+    if not device_backend(configuration).uses_paths or not timeout_ms:
+        return
+    locate_device(configuration)
+    if os.path.exists(configuration["brightness"]["path"]):
+        return
+    # without a device matching 'match', there is no path worth naming
+    awaited = f"'{configuration['brightness']['path']}'"
+    if "match" in configuration:
+        awaited = "an LED device matching 'match'"
+    if dry_run:
+        print(f"Would wait up to {timeout_ms} ms for {awaited} to appear")
+        return
+    logger.info(f"Waiting up to {timeout_ms} ms for {awaited} to appear")
+    try:
+        monitor = socket.socket(socket.AF_NETLINK, socket.SOCK_DGRAM, NETLINK_KOBJECT_UEVENT)
+        monitor.bind((0, 1))
+    except (OSError, AttributeError) as e:
+        logger.debug(f"Unable to listen for uevents ({e}), polling instead")
+        monitor = None
+    deadline = time.monotonic() + timeout_ms / 1000
+    try:
+        # checked again once listening, so a device that appeared meanwhile is not waited for
+        while not os.path.exists(configuration["brightness"]["path"]):
+            remaining = deadline - time.monotonic()
+            if remaining <= 0:
+                logger.warning(f"Gave up after {timeout_ms} ms waiting for {awaited} to appear")
+                return
+            interval = min(remaining, DEVICE_POLL_INTERVAL_MS / 1000)
+            if monitor is None:
+                time.sleep(interval)
+            elif select.select([monitor], [], [], interval)[0]:
+                # which device it was doesn't matter, as the path is what decides
+                monitor.recv(65536)
+            locate_device(configuration)
+    finally:
+        if monitor is not None:
+            monitor.close()
+    path = configuration["brightness"]["path"]
+    logger.debug(f"'{path}' appeared after {round(timeout_ms - (deadline - time.monotonic()) * 1000)} ms")
+
+
+def do_post(configuration: dict, args: argparse.Namespace) -> dict:
+    # unlike the sleep state, a slot or history entry is only ever restored on purpose, so falling back to defaults
+    # (or applying power profiles and the schedule over it) would be a surprise
+    chosen = args.slot is not None or getattr(args, "history", None) is not None
+    if chosen and load_state_file(configuration) is None:
+        described = f"Slot {args.slot}" if args.slot is not None else f"History entry {args.history}"
+        raise StateError(f"{described} has no readable state in '{configuration['state_path']}'")
+    # before anything reads the device, e.g. its max_brightness for rescaling the saved brightness
+    wait = getattr(args, "wait_for_device", None)
+    wait_for_device(
+        configuration, configuration.get("wait_for_device_ms", 0) if wait is None else wait, dry_run=args.dry_run
+    )
+    # the schedule sits beside the configured state_path, whichever state file is restored
+    try:
+        schedule = combined_schedule(configuration) if not chosen else {}
+    except StateError as e:
+        logger.warning(f"Ignoring the entries added with 'schedule add': {e}")
+        schedule = configuration.get("schedule", {})
+    if not chosen:
+        configuration = dict(configuration, state_path=freshest_state_path(configuration))
+    if not chosen and stale_state(configuration):
+        state = default_state(configuration)
+    else:
+        state = read_state(configuration)
+    power = configuration.get("power", {})
+    if not chosen and ({"ac", "battery"} & set(power)):
+        source = power_source(pathlib.PosixPath(power.get("path", POWER_SUPPLY_PATH)))
+        if source in power:
+            logger.info(f"On {'AC' if source == 'ac' else source} power, applying profile '{power[source]}'")
+            state = profile_state(configuration, power[source], state)
+        else:
+            logger.debug(f"Power source is {source or 'unknown'}, restoring the saved state as it is")
+    due = due_entry(schedule)
+    if not chosen and due is not None:
+        logger.info(f"Applying the entry scheduled at {due[0]}")
+        state = scheduled_state(configuration, due[1], state)
+    run_hook(configuration, "before_restore", state, dry_run=args.dry_run)
+    # some firmware resets the LEDs a moment after resume, undoing an immediate restore
+    for key in ("restore_delay_ms", "reapply_delay_ms"):
+        delay = configuration.get(key, 0)
+        if key == "reapply_delay_ms" and delay == 0:
+            break
+        if delay and args.dry_run:
+            print(f"Would wait {delay} ms ({key})")
+        elif delay:
+            logger.debug(f"Waiting {delay} ms ({key})")
+            time.sleep(delay / 1000)
+        apply_state(configuration, state, dry_run=args.dry_run, parts=transition_parts(configuration, "post"))
+    run_hook(configuration, "after_restore", state, dry_run=args.dry_run)
+    return state
+
+
+def do_set(configuration: dict, args: argparse.Namespace) -> dict:
+    if args.color is not None and not color_enabled(configuration):
+        raise UsageError(f"Color is not managed for {configuration['device']} ('color.enabled' is false)")
+    state = read_state_document("-") if args.stdin else read_hardware(configuration)
+    if args.brightness is not None:
+        state["brightness"] = resolve_brightness(configuration, args.brightness)
+    if args.color is not None:
+        state["color"] = args.color
+    for zone, color in args.zone or []:
+        if zone not in configuration.get("zones", {}):
+            expected = ", ".join(configuration.get("zones", {})) or "(none configured)"
+            raise UsageError(f"Unknown zone '{zone}' for {configuration['device']}, expected one of: {expected}")
+        state["zones"] = dict(state.get("zones", {}), **{zone: color})
+    apply_state(configuration, state, dry_run=args.dry_run)
+    write_state(configuration, state, "set", dry_run=args.dry_run)
+    return state
+
+
+def do_history(configuration: dict, args: argparse.Namespace) -> dict:
+    entries = []
+    for entry in range(1, configuration.get("history", DEFAULT_HISTORY) + 1):
+        path = history_state_path(configuration["state_path"], entry)
+        document = load_state_file(dict(configuration, state_path=path))
+        if document is None:
+            continue
+        saved = document.get("saved_at")
+        if type(saved) is not str:
+            # states from before they were stamped
+            saved = datetime.datetime.fromtimestamp(os.stat(path).st_mtime).isoformat(timespec="seconds")
+        entries.append({"entry": entry, "saved": saved, "path": path, "state": document})
+    if not args.json:
+        if not entries:
+            print(f"No history beside '{configuration['state_path']}'")
+        for entry in entries:
+            values = state_values(entry["state"]) or {}
+            zones = "".join(f", {zone} zone {color}" for zone, color in entry["state"].get("zones", {}).items())
+            saved_by = f" by {entry['state']['saved_by']}" if type(entry["state"].get("saved_by")) is str else ""
+            print(
+                f"{entry['entry']}: saved {entry['saved']}{saved_by}: brightness {values.get('brightness', '-')}, "
+                f"color {values.get('color', '-')}{zones}"
+            )
+    return {"entries": entries}
+
+
+def do_undo(configuration: dict, args: argparse.Namespace) -> dict:
+    document = load_state_file(configuration)
+    previous = state_values((document or {}).get("previous"))
+    if previous is None:
+        raise StateError(f"Nothing to undo in '{configuration['state_path']}'")
+    state = dict(previous)
+    current = state_values(document)
+    if current is not None:
+        # undoing again flips back
+        state["previous"] = current
+    apply_state(configuration, state, dry_run=args.dry_run)
+    write_state(configuration, state, "undo", dry_run=args.dry_run)
+    return state
+
+
+def do_toggle(configuration: dict, args: argparse.Namespace) -> dict:
+    state = read_hardware(configuration)
+    if int(state["brightness"]) != 0:
+        state["last_brightness"] = state["brightness"]
+        state["brightness"] = "0"
+    else:
+        state["brightness"] = saved_last_brightness(configuration) or brightness_default(configuration)
+    apply_state(configuration, state, dry_run=args.dry_run)
+    write_state(configuration, state, "toggle", dry_run=args.dry_run)
+    return state
+
+
+def do_brightness(configuration: dict, args: argparse.Namespace) -> dict:
+    levels = [int(level) for level in configuration["brightness"].get("levels", [])]
+    step = args.step if args.step is not None else brightness_step(configuration)
+    state = read_hardware(configuration)
+    current = int(state["brightness"])
+    if levels and args.step is None:
+        # a calibrated curve: move to the next distinct level, staying put at either end
+        if args.direction == "up":
+            brightness = min([level for level in levels if level > current] or [max(current, levels[-1])])
+        else:
+            brightness = max([level for level in levels if level < current] or [min(current, levels[0])])
+    else:
+        brightness = current + (step if args.direction == "up" else -step)
+    state["brightness"] = str(max(0, min(brightness, brightness_limit(configuration))))
+    apply_state(configuration, state, dry_run=args.dry_run)
+    write_state(configuration, state, "brightness", dry_run=args.dry_run)
+    return state
+
+
+def do_color(configuration: dict, args: argparse.Namespace) -> dict:
+    if not color_enabled(configuration):
+        raise UsageError(f"Color is not managed for {configuration['device']} ('color.enabled' is false)")
+    palette = color_palette(configuration)
+    state = read_hardware(configuration)
+    current = state["color"].upper()
+    if current in palette:
+        index = palette.index(current) + (1 if args.direction == "next" else -1)
+    else:
+        # start from whichever end of the palette the user is moving towards
+        index = 0 if args.direction == "next" else -1
+    state["color"] = palette[index % len(palette)]
+    apply_state(configuration, state, dry_run=args.dry_run)
+    write_state(configuration, state, "color", dry_run=args.dry_run)
+    return state
+
+
+def do_tui(configuration: dict, args: argparse.Namespace) -> dict:
+    if curses is None:
+        raise UsageError("'tui' needs Python's curses module, which this Python was built without")
+    original = read_hardware(configuration)
+    # as in 'color next', some drivers report lowercase hex, which the palette would not otherwise match
+    state = dict(original, color=original["color"].upper())
+    palette = color_palette(configuration)
+    # palette entries that are one of the eight basic terminal colors are drawn as a swatch, any other as its hex
+    terminal_colors = {
+        "000000": curses.COLOR_BLACK, "FF0000": curses.COLOR_RED, "00FF00": curses.COLOR_GREEN,
+        "FFFF00": curses.COLOR_YELLOW, "0000FF": curses.COLOR_BLUE, "FF00FF": curses.COLOR_MAGENTA,
+        "00FFFF": curses.COLOR_CYAN, "FFFFFF": curses.COLOR_WHITE,
+    }
+    max_brightness = brightness_limit(configuration)
+    step = brightness_step(configuration)
+
+    def apply(values: dict) -> None:
+        # dry-run previews would scribble over the screen
+        with contextlib.redirect_stdout(io.StringIO()):
+            apply_state(configuration, values, dry_run=args.dry_run)
+
+    def run(screen) -> bool:
+        curses.curs_set(0)
+        curses.set_escdelay(25)
+        if curses.has_colors():
+            curses.use_default_colors()
+            for index, color in enumerate(terminal_colors.values(), start=1):
+                curses.init_pair(index, color, color)
+        row = 0
+        while True:
+            brightness = int(state["brightness"])
+            width = max(10, min(50, screen.getmaxyx()[1] - 30))
+            filled = round(width * brightness / max_brightness) if max_brightness else 0
+            screen.erase()
+            screen.addstr(0, 0, "Keyboard backlight", curses.A_BOLD)
+            screen.addstr(2, 0, "> " if row == 0 else "  ")
+            screen.addstr(2, 2, f"Brightness [{'#' * filled}{'-' * (width - filled)}] {brightness}/{max_brightness}")
+            screen.addstr(3, 0, "> " if row == 1 else "  ")
+            screen.addstr(3, 2, "Color      ")
+            for entry in palette:
+                marker = "[" if entry == state["color"] else " "
+                screen.addstr(marker)
+                if curses.has_colors() and entry in terminal_colors:
+                    pair = list(terminal_colors).index(entry) + 1
+                    screen.addstr("  ", curses.color_pair(pair))
+                else:
+                    screen.addstr(entry)
+                screen.addstr("]" if entry == state["color"] else " ")
+            screen.addstr(4, 13, state["color"])
+            screen.addstr(6, 0, "up/down: select   left/right: adjust (shift for fine steps)")
+            screen.addstr(7, 0, "enter/q: save and quit   esc: discard and quit")
+            screen.refresh()
+            key = screen.getch()
+            if key in (ord("q"), ord("Q"), curses.KEY_ENTER, 10, 13):
+                return True
+            if key == 27:
+                return False
+            if key in (curses.KEY_UP, curses.KEY_DOWN, ord("\t")):
+                row = 1 - row
+                continue
+            left = key in (curses.KEY_LEFT, curses.KEY_SLEFT)
+            right = key in (curses.KEY_RIGHT, curses.KEY_SRIGHT)
+            if not (left or right):
+                continue
+            if row == 0:
+                amount = 1 if key in (curses.KEY_SLEFT, curses.KEY_SRIGHT) else step
+                state["brightness"] = str(max(0, min(brightness + (amount if right else -amount), max_brightness)))
+            else:
+                index = palette.index(state["color"]) if state["color"] in palette else -1
+                state["color"] = palette[(index + (1 if right else -1)) % len(palette)]
+            apply(state)
+
+    if curses.wrapper(run):
+        write_state(configuration, state, "tui", dry_run=args.dry_run)
+        return state
+    apply(original)
+    return original
+
+
+def profile_state(configuration: dict, name: str, state: dict) -> dict:
+    # the state with a profile's settings applied over it
+    profiles = configuration.get("profiles", {})
+    if name not in profiles:
+        known = ", ".join(profiles) or "(none configured)"
+        raise UsageError(f"Unknown profile '{name}', expected one of: {known}")
+    profile = profiles[name]
+    state = dict(state)
+    if "brightness" in profile:
+        state["brightness"] = resolve_brightness(configuration, profile["brightness"])
+    if "color" in profile:
+        state["color"] = profile["color"]
+    if "zones" in profile:
+        state["zones"] = dict(state.get("zones", {}), **profile["zones"])
+    return state
+
+
+def do_profile(configuration: dict, args: argparse.Namespace) -> dict:
+    profiles = configuration.get("profiles", {})
+    if args.action == "list":
+        if not args.json:
+            for name, profile in profiles.items():
+                settings = [f"{key} {profile[key]}" for key in ("brightness", "color") if key in profile]
+                settings += [f"{zone} zone {color}" for zone, color in profile.get("zones", {}).items()]
+                print(f"{name}: {', '.join(settings)}")
+        return profiles
+    state = profile_state(configuration, args.name, read_hardware(configuration))
+    apply_state(configuration, state, dry_run=args.dry_run)
+    write_state(configuration, state, "profile", dry_run=args.dry_run)
+    return state
+
+
+def do_snapshot(configuration: dict, args: argparse.Namespace) -> dict:
+    state_path = pathlib.PosixPath(configuration["state_path"])
+    if args.action == "list":
+        prefix = f"{state_path.stem}.snapshot-"
+        snapshots = {}
+        for path in sorted(state_path.parent.glob(f"{prefix}*{state_path.suffix}")):
+            name = path.name[len(prefix):len(path.name) - len(state_path.suffix)]
+            document = load_state_file(dict(configuration, state_path=str(path)))
+            if document is not None:
+                snapshots[name] = {"path": str(path), "state": document}
+        if not args.json:
+            if not snapshots:
+                print(f"No snapshots beside '{state_path}'")
+            for name, snapshot in snapshots.items():
+                values = state_values(snapshot["state"]) or {}
+                saved_at = snapshot["state"].get("saved_at")
+                saved = f"saved {saved_at}: " if type(saved_at) is str else ""
+                print(f"{name}: {saved}brightness {values.get('brightness', '-')}, color {values.get('color', '-')}")
+        return snapshots
+    # a snapshot has no history or backups of its own, and is not something to fall back to /run for
+    snapshot = dict(
+        configuration,
+        state_path=snapshot_state_path(configuration["state_path"], args.name),
+        runtime_state_path=None,
+        history=0,
+        backups=0,
+    )
+    if args.action == "save":
+        state = read_hardware(configuration)
+        write_state(snapshot, state, "snapshot", dry_run=args.dry_run)
+        return state
+    if load_state_file(snapshot) is None:
+        raise StateError(f"No snapshot '{args.name}' in '{snapshot['state_path']}'")
+    if args.action == "delete":
+        if args.dry_run:
+            print(f"Would remove '{snapshot['state_path']}'")
+        else:
+            with state_lock(snapshot, exclusive=True):
+                pathlib.PosixPath(snapshot["state_path"]).unlink()
+            logger.info(f"Removed snapshot '{args.name}'")
+        return {"name": args.name}
+    # like restoring a slot, this leaves the state used across sleep alone
+    state = read_state(snapshot)
+    apply_state(configuration, state, dry_run=args.dry_run)
+    return state
+
+
+def schedule_path(configuration: dict) -> pathlib.PosixPath:
+    return pathlib.PosixPath(configuration["state_path"]).with_name("schedule.json")
+
+
+def read_schedule(configuration: dict) -> dict:
+    path = schedule_path(configuration)
+    try:
+        with open(path, "rt") as schedule_file:
+            schedule = json.load(schedule_file)
+    except FileNotFoundError:
+        return {}
+    except OSError as e:
+        raise os_error(StateError, f"Unable to read schedule '{path}'", e)
+    except ValueError as e:
+        raise StateError(f"Unable to parse schedule '{path}': {e}")
+    if type(schedule) is not dict:
+        raise StateError(f"'{path}' does not contain a schedule object")
+    return schedule
+
+
+def write_schedule(configuration: dict, schedule: dict, dry_run: bool = False):
+    path = schedule_path(configuration)
+    schedule = dict(sorted(schedule.items()))
+    if dry_run:
+        print(f"Would write schedule to '{path}': {json.dumps(schedule)}")
+        return
+    try:
+        write_state_file(configuration, path, schedule)
+    except OSError as e:
+        raise os_error(StateError, f"Unable to write schedule '{path}'", e)
+    logger.info(f"Saved schedule to '{path}'")
+
+
+def combined_schedule(configuration: dict) -> dict:
+    # entries added with 'schedule add' replace any configured at the same time
+    return dict(configuration.get("schedule", {}), **read_schedule(configuration))
+
+
+def due_entry(schedule: dict) -> tuple:
+    # the (time, entry) of the latest entry at or before now, wrapping around to yesterday's last entry, or None
+    if not schedule:
+        return None
+    now = datetime.datetime.now().strftime("%H:%M")
+    due = [at for at in sorted(schedule) if at <= now] or sorted(schedule)
+    return due[-1], schedule[due[-1]]
+
+
+def scheduled_state(configuration: dict, entry: dict, state: dict) -> dict:
+    # the state with a schedule entry's settings applied over it
+    if "profile" in entry:
+        state = profile_state(configuration, entry["profile"], state)
+    state = dict(state)
+    if "brightness" in entry:
+        state["brightness"] = resolve_brightness(configuration, entry["brightness"])
+    if "color" in entry:
+        state["color"] = entry["color"]
+    return state
+
+
+def do_schedule(configuration: dict, args: argparse.Namespace) -> dict:
+    configured = configuration.get("schedule", {})
+    schedule = read_schedule(configuration)
+    if args.action == "list":
+        combined = combined_schedule(configuration)
+        if not args.json:
+            for at, entry in sorted(combined.items()):
+                settings = ", ".join(
+                    f"{key} {entry[key]}" for key in ("profile", "brightness", "color") if key in entry
+                )
+                origin = "" if at in schedule else "  (configuration)"
+                print(f"{at}: {settings}{origin}")
+        return combined
+    if args.action == "add":
+        entry = {}
+        if args.profile is not None:
+            # checked now, but looked up when applied, so later edits to the profile take effect
+            profile_state(configuration, args.profile, {})
+            entry["profile"] = args.profile
+        if args.brightness is not None:
+            entry["brightness"] = args.brightness
+        if args.color is not None:
+            entry["color"] = args.color
+        # one entry per time, so adding at an existing time replaces it
+        schedule[args.time] = entry
+        write_schedule(configuration, schedule, dry_run=args.dry_run)
+        return schedule
+    if args.action == "remove":
+        if args.time in configured and args.time not in schedule:
+            raise UsageError(f"The entry at {args.time} is set in the configuration, edit it there instead")
+        if args.time not in schedule:
+            known = ", ".join(sorted(schedule)) or "(none scheduled)"
+            raise UsageError(f"Nothing scheduled at {args.time}, expected one of: {known}")
+        del schedule[args.time]
+        write_schedule(configuration, schedule, dry_run=args.dry_run)
+        return schedule
+    due = due_entry(combined_schedule(configuration))
+    if due is None:
+        raise StateError(f"Nothing scheduled in the configuration or '{schedule_path(configuration)}'")
+    logger.info(f"Applying the entry scheduled at {due[0]}")
+    state = scheduled_state(configuration, due[1], read_hardware(configuration))
+    apply_state(configuration, state, dry_run=args.dry_run)
+    write_state(configuration, state, "schedule", dry_run=args.dry_run)
+    return state
+
+
+def do_export(configuration: dict, args: argparse.Namespace) -> dict:
+    document = load_state_file(configuration)
+    if document is None:
+        raise StateError(f"No readable state in '{configuration['state_path']}'")
+    if args.defaults:
+        document["defaults"] = {key: configuration[key]["default"] for key in ("brightness", "color")}
+    if args.json:
+        return document
+    if args.format == "env":
+        for name, value in state_environment(configuration, document).items():
+            print(f"{name}={shlex.quote(value)}")
+    else:
+        print(json.dumps(document, indent=2))
+    return document
+
+
+def state_environment(configuration: dict, document: dict) -> dict:
+    # an exported state as variables, for eval in shell scripts or a systemd EnvironmentFile=
+    variables = {"S76_KBD_DEVICE": configuration["device"]}
+    for key in ("brightness", "color", "last_brightness", "saved_at", "saved_by", "hostname"):
+        if type(document.get(key)) is str:
+            variables[f"S76_KBD_{key.upper()}"] = document[key]
+    if type(document.get("enabled")) is bool:
+        variables["S76_KBD_ENABLED"] = "1" if document["enabled"] else "0"
+    for zone, color in (document.get("zones") or {}).items():
+        variables[f"S76_KBD_ZONE_{zone.upper()}"] = str(color)
+    # not named S76_KBD_*_DEFAULT, as those override the configured defaults, which loading this as an
+    # EnvironmentFile= for the tool itself would then quietly do
+    for key, value in document.get("defaults", {}).items():
+        variables[f"S76_KBD_DEFAULT_{key.upper()}"] = value
+    return variables
+
+
+def do_import(configuration: dict, args: argparse.Namespace) -> dict:
+    state = read_state_document(args.file)
+    # any exported defaults are informational; the configuration is left alone
+    if args.apply:
+        apply_state(configuration, state, dry_run=args.dry_run)
+    write_state(configuration, state, "import", dry_run=args.dry_run)
+    return state
+
+
+def system76_power_settings(path: pathlib.PosixPath) -> dict:
+    # a settings file as JSON, TOML, KEY=VALUE lines or a bare value, flattened to lower-case keys such as
+    # 'keyboard_brightness', since all that matters is whatever brightness and color it holds
+    try:
+        text = path.read_text()
+    except OSError as e:
+        raise os_error(StateError, f"Unable to read '{path}'", e)
+    settings = None
+    with contextlib.suppress(ValueError):
+        settings = json.loads(text)
+    if settings is None and tomllib is not None:
+        with contextlib.suppress(ValueError):
+            settings = tomllib.loads(text)
+    if settings is None:
+        lines = [line.strip() for line in text.splitlines() if line.strip() and not line.strip().startswith("#")]
+        if len(lines) == 1 and not re.search(r"[=:]", lines[0]):
+            # e.g. a file holding just the brightness, named for it
+            settings = lines[0]
+        else:
+            settings = dict(re.split(r"\s*[=:]\s*", line, maxsplit=1) for line in lines if re.search(r"[=:]", line))
+    return flatten_system76_power_settings(settings, path.name)
+
+
+def flatten_system76_power_settings(settings, key: str) -> dict:
+    if type(settings) is not dict:
+        return {key.lower(): settings}
+    flat = {}
+    for inner, value in settings.items():
+        flat.update(flatten_system76_power_settings(value, f"{key}_{inner}"))
+    return flat
+
+
+def do_migrate_from_system76_power(configuration: dict, args: argparse.Namespace) -> dict:
+    if args.path is not None:
+        paths = [pathlib.PosixPath(args.path)]
+    else:
+        paths = [
+            path
+            for directory in SYSTEM76_POWER_PATHS
+            for path in sorted(directory.rglob("*"))
+            if path.is_file() and re.search(r"kbd|keyboard|backlight", str(path.relative_to(directory)), re.I)
+        ]
+        if not paths:
+            searched = ", ".join(f"'{directory}'" for directory in SYSTEM76_POWER_PATHS)
+            raise StateError(f"No system76-power keyboard settings found in {searched}; give the file to read")
+    state = read_hardware(configuration)
+    found = {}
+    for path in paths:
+        for key, value in system76_power_settings(path).items():
+            value = str(value).strip().strip('"')
+            if re.search(r"(brightness|level)$", key) and "brightness" not in found and re.fullmatch(r"\d+%?", value):
+                brightness = resolve_brightness(configuration, value)
+                if valid_brightness(brightness):
+                    found["brightness"] = brightness
+                    logger.info(f"Found brightness {brightness} in '{path}'")
+            elif re.search(r"colou?r$", key) and "color" not in found and valid_color(parse_color(value)):
+                found["color"] = parse_color(value)
+                logger.info(f"Found color {found['color']} in '{path}'")
+    if not found:
+        searched = ", ".join(f"'{path}'" for path in paths)
+        raise StateError(f"No keyboard brightness or color found in {searched}")
+    if "color" in found and not color_enabled(configuration):
+        logger.info(f"Ignoring the color, as it is not managed for {configuration['device']}")
+        del found["color"]
+    state.update(found)
+    if args.apply:
+        apply_state(configuration, state, dry_run=args.dry_run)
+    write_state(configuration, state, "migrate-from-system76-power", dry_run=args.dry_run)
+    return state
+
+
+def do_identify(configuration: dict, args: argparse.Namespace) -> dict:
+    original = read_hardware(configuration)
+    on = dict(original, brightness=str(min(read_max_brightness(configuration), 255)))
+    off = dict(original, brightness="0")
+    try:
+        for _ in range(args.count):
+            apply_state(configuration, off, dry_run=args.dry_run)
+            time.sleep(args.interval)
+            apply_state(configuration, on, dry_run=args.dry_run)
+            time.sleep(args.interval)
+    finally:
+        apply_state(configuration, original, dry_run=args.dry_run)
+    return original
+
+
+def do_demo(configuration: dict, args: argparse.Namespace) -> dict:
+    original = read_hardware(configuration)
+    max_brightness = min(read_max_brightness(configuration), 255)
+    sequence = [
+        {"brightness": str(max_brightness), "color": color}
+        for color in color_palette(configuration)
+    ]
+    sequence += [
+        {"brightness": str(max_brightness * percent // 100), "color": original["color"]}
+        for percent in (0, 25, 50, 75, 100, 75, 50, 25)
+    ]
+    try:
+        for state in sequence:
+            logger.info(f"demo: brightness {state['brightness']}, color {state['color']}")
+            apply_state(configuration, state, dry_run=args.dry_run)
+            time.sleep(args.interval)
+    finally:
+        apply_state(configuration, original, dry_run=args.dry_run)
+    return original
+
+
+def do_reset(configuration: dict, args: argparse.Namespace) -> dict:
+    state = default_state(configuration)
+    apply_state(configuration, state, dry_run=args.dry_run)
+    write_state(configuration, state, "reset", dry_run=args.dry_run)
+    return state
+
+
+def do_calibrate(configuration: dict, args: argparse.Namespace) -> dict:
+    config_path = configuration_file(args.config)
+    original = read_hardware(configuration)
+    max_brightness = min(read_max_brightness(configuration), 255)
+    candidates = sorted({max_brightness * index // args.steps for index in range(args.steps + 1)})
+    levels = [candidates[0]]
+    print("Answer whether each level looks brighter than the last one kept. Enter accepts, 'n' skips.")
+    try:
+        for value in candidates[1:]:
+            apply_state(configuration, dict(original, brightness=str(value)), dry_run=args.dry_run)
+            try:
+                answer = input(f"Brightness {value}: visibly brighter than {levels[-1]}? [Y/n] ")
+            except EOFError:
+                raise UsageError("calibrate: input ended before every level was answered")
+            if answer.strip().lower() in ("", "y", "yes"):
+                levels.append(value)
+    finally:
+        apply_state(configuration, original, dry_run=args.dry_run)
+    levels = [str(level) for level in levels]
+    print(f"Distinct levels: {' '.join(levels)}")
+
+    # update the file itself rather than the loaded configuration, so no defaults get written into it
+    if config_path.suffix == ".toml":
+        raise ConfigurationError(
+            f"Unable to update TOML configuration '{config_path}'; add 'levels = {json.dumps(levels)}' "
+            "to its [brightness] table by hand"
+        )
+    if config_path.suffix in (".yaml", ".yml"):
+        raise ConfigurationError(
+            f"Unable to update YAML configuration '{config_path}'; add 'levels: {json.dumps(levels)}' "
+            "to its brightness mapping by hand"
+        )
+    try:
+        with open(config_path, "rt") as config_file:
+            document = json.load(config_file)
+    except FileNotFoundError:
+        document = copy.deepcopy(DEFAULT_CONFIGURATION)
+    except OSError as e:
+        raise os_error(ConfigurationError, f"Unable to read configuration '{config_path}'", e)
+    except ValueError as e:
+        raise ConfigurationError(f"Unable to parse configuration '{config_path}': {e}")
+    table = document
+    if type(document) is dict and type(document.get("devices")) is dict:
+        table = document["devices"].get(configuration["device"])
+    if type(table) is not dict or type(table.get("brightness")) is not dict:
+        raise ConfigurationError(f"'{config_path}' has no 'brightness' object to store the levels in")
+    table["brightness"]["levels"] = levels
+    if args.dry_run:
+        print(f"Would write 'brightness.levels' to '{config_path}': {json.dumps(levels)}")
+        return {"levels": levels, "path": str(config_path)}
+    try:
+        with open(config_path, "wt") as out_file:
+            json.dump(document, out_file, indent=2)
+            out_file.write("\n")
+    except OSError as e:
+        raise os_error(ConfigurationError, f"Unable to write configuration '{config_path}'", e)
+    logger.info(f"Saved brightness levels to '{config_path}'")
+    return {"levels": levels, "path": str(config_path)}
+
+
+def do_check_config(configuration: dict, args: argparse.Namespace) -> dict:
+    problems = []
+    if args.config is None:
+        # with --lenient, read_configuration() skips unusable files, so report those explicitly
+        for config_path in configuration_paths():
+            if not config_path.exists():
+                continue
+            # noinspection PyBroadException
+            try:
+                load_configuration_file(config_path)
+                break
+            except Exception as e:
+                problems.append(f"{config_path} is ignored because it cannot be read: {e}")
+        for drop_in_path in sorted(DROP_IN_PATH.glob("*")) if DROP_IN_PATH.is_dir() else []:
+            if drop_in_path.suffix not in (".json", ".toml", ".yaml", ".yml"):
+                continue
+            # noinspection PyBroadException
+            try:
+                if type(load_configuration_file(drop_in_path)) is not dict:
+                    problems.append(f"{drop_in_path} is ignored because it is not an object")
+            except Exception as e:
+                problems.append(f"{drop_in_path} is ignored because it cannot be read: {e}")
+    problems.extend(validate_configuration(configuration))
+    if problems:
+        raise ValidationError(f"{len(problems)} configuration problem(s) found", problems)
+    print("Configuration OK")
+    return {"problems": []}
+
+
+def device_link(path: pathlib.PosixPath) -> str:
+    # the name of what a sysfs symlink such as 'device' or 'driver' points at, as udev reports it
+    return pathlib.PosixPath(os.path.realpath(path)).name if path.exists() else None
+
+
+def discover_devices(leds_path: pathlib.PosixPath, keyboards_only: bool = True) -> list:
+    # every LED class device that is a keyboard backlight, by its name or by belonging to one of the System76
+    # drivers, as udev would enumerate them (the sysfs links it reads are read directly, as no binding to libudev
+    # comes with Python); without 'keyboards_only', every LED class device, for 'match' to pick from
+    devices = []
+    found = [
+        device
+        for device in (sorted(leds_path.iterdir()) if leds_path.is_dir() else [])
+        if not keyboards_only
+        or "kbd_backlight" in device.name
+        or device_link(device / "device" / "driver") in DRIVER_MODULES
+    ]
+    if (TUXEDO_PLATFORM_PATH / "brightness").exists():
+        found.append(TUXEDO_PLATFORM_PATH)
+    for device in found:
+        try:
+            max_brightness = int(read_attribute(str(device / "max_brightness")))
+        except (StatemgrError, ValueError):
+            max_brightness = None
+        attributes = [name for name in LED_ATTRIBUTES if (device / name).exists()]
+        if device == TUXEDO_PLATFORM_PATH:
+            color_format = "0x"
+        elif "multi_intensity" in attributes and "color" not in attributes:
+            color_format = "rgb"
+        else:
+            color_format = "hex"
+        # the device the LED belongs to (e.g. the ACPI device 17761776:00) and its driver, e.g. system76_acpi or
+        # asus-nb-wmi
+        parent = device / "device" if device != TUXEDO_PLATFORM_PATH else device
+        devices.append({
+            "name": device.name,
+            "path": str(device),
+            "attributes": attributes,
+            "max_brightness": max_brightness,
+            "color_format": color_format,
+            "driver": device_link(parent / "driver"),
+            "parent": device_link(parent),
+        })
+    return devices
+
+
+def preferred_device(devices: list):
+    # the one of several discovered devices the built-in defaults use: one with colors (or color zones) before one
+    # without, and System76's (by driver, or failing that by name) before other vendors'
+    if not devices:
+        return None
+    return min(
+        devices,
+        key=lambda device: (
+            not any(
+                attribute == device_color_attribute(device) or attribute.startswith("color_")
+                for attribute in device["attributes"]
+            ),
+            device.get("driver") not in DRIVER_MODULES,
+            not device["name"].startswith("system76"),
+        ),
+    )
+
+
+def device_color_attribute(device: dict) -> str:
+    return "multi_intensity" if device["color_format"] == "rgb" else "color"
+
+
+def discovered_configuration(device: dict) -> dict:
+    # the brightness and color tables for a discovered device, over the built-in defaults
+    directory = pathlib.PosixPath(device["path"])
+    color = {"path": str(directory / device_color_attribute(device)), "format": device["color_format"]}
+    if device_color_attribute(device) not in device["attributes"]:
+        # zones, if it has any, are found as usual
+        color["enabled"] = False
+    brightness = {"path": str(directory / "brightness")}
+    max_brightness = device["max_brightness"]
+    if max_brightness and max_brightness < 255:
+        # as in 'init-config', the default keeps its proportion of a device with fewer levels
+        brightness["default"] = str(round(max_brightness * int(DEFAULT_CONFIGURATION["brightness"]["default"]) / 255))
+    return {"brightness": brightness, "color": color}
+
+
+def configuration_template(devices: list) -> str:
+    # a commented TOML configuration with the built-in defaults, using whichever devices were found
+    quote = json.dumps  # JSON strings are valid TOML basic strings
+    defaults = DEFAULT_CONFIGURATION
+    lines = [
+        f"# s76-kbd-led-statemgr configuration, written by 'init-config' on {datetime.date.today().isoformat()}.",
+        "# Every value sysfs sees is a string, so brightness and colors are quoted.",
+        "",
+        "# the configuration format, so that later releases can upgrade it",
+        f"version = {CONFIGURATION_VERSION}",
+        "# where 'save' keeps the keyboard state, and 'restore' finds it",
+        f"state_path = {quote(defaults['state_path'])}",
+        "# for firmware that resets the LEDs just after resume: wait before restoring, then restore again",
+        "# restore_delay_ms = 500",
+        "# reapply_delay_ms = 2000",
+        "# for an LED device that is slow to come back after resume: how long to wait for it before restoring",
+        "# wait_for_device_ms = 5000",
+        "# how often to retry writes the LED device refuses as busy or missing, and the first wait (it then doubles)",
+        f"# retries = {DEFAULT_RETRIES}",
+        f"# retry_interval_ms = {DEFAULT_RETRY_INTERVAL_MS}",
+    ]
+    if not devices:
+        lines += ["", f"# no *kbd_backlight* devices were found in {LEDS_PATH}, so these are the built-in paths"]
+        devices = [{
+            "name": pathlib.PosixPath(defaults["brightness"]["path"]).parent.name,
+            "path": str(pathlib.PosixPath(defaults["brightness"]["path"]).parent),
+            "attributes": ["brightness", "color"],
+            "max_brightness": None,
+            "color_format": "hex",
+            "driver": None,
+            "parent": None,
+        }]
+    elif len(devices) > 1:
+        lines += ["", "# one table per LED device; commands take --device NAME, and save/restore cover them all"]
+    for device in devices:
+        prefix = f"devices.{quote(device['name'])}." if len(devices) > 1 else ""
+        directory = pathlib.PosixPath(device["path"])
+        default = defaults["brightness"]["default"]
+        max_brightness = device["max_brightness"]
+        if max_brightness and max_brightness < 255:
+            # keep the default at the same proportion of a device with fewer levels
+            default = str(round(max_brightness * int(default) / 255))
+        if device["driver"]:
+            lines += [
+                "",
+                "# to find the device by its driver rather than by the paths below, if a kernel renames it",
+                f"# [{prefix}match]",
+                f"# driver = {quote(device['driver'])}",
+            ]
+        lines += [
+            "",
+            f"[{prefix}brightness]",
+            f"path = {quote(str(directory / 'brightness'))}",
+            f"# applied when there is no saved state, as 0-{min(max_brightness or 255, 255)} or a percentage",
+            f"default = {quote(default)}",
+            "# how far 'brightness up' and 'brightness down' move",
+            f"step = {max(1, round((max_brightness or 255) * 16 / 255))}",
+            "# nothing brighter than this is ever written, as a number or a percentage of max_brightness",
+            '# max = "60%"',
+            "",
+            f"[{prefix}color]",
+        ]
+        color_attribute = device_color_attribute(device)
+        if color_attribute not in device["attributes"]:
+            lines += ["# this device has no color attribute, so only brightness is managed", "enabled = false"]
+        else:
+            lines += [
+                f"path = {quote(str(directory / color_attribute))}",
+                "# RRGGBB, or a CSS color name such as \"red\"",
+                f"default = {quote(defaults['color']['default'])}",
+                "# what 'color next' and 'color prev' cycle through",
+                f"palette = [{', '.join(quote(color) for color in DEFAULT_PALETTE)}]",
+            ]
+        if device["color_format"] != "hex":
+            lines += [
+                "# how the driver spells colors: \"0x\" for 0xRRGGBB, \"rgb\" for decimal \"R G B\"",
+                f"format = {quote(device['color_format'])}",
+            ]
+        for attribute in device["attributes"]:
+            if attribute.startswith("color_"):
+                zone = attribute[len("color_"):]
+                lines += ["", f"[{prefix}zones.{zone}]", f"default = {quote(defaults['color']['default'])}"]
+    lines += [
+        "",
+        "# named settings for 'profile apply NAME'; either key may be left out",
+        "# [profiles.night]",
+        '# brightness = "10%"',
+        '# color = "red"',
+    ]
+    return "\n".join(lines) + "\n"
+
+
+def do_init_config(configuration: dict, args: argparse.Namespace) -> dict:
+    text = configuration_template(discover_devices(LEDS_PATH))
+    if args.path == "-":
+        sys.stdout.write(text)
+        return {"path": None}
+    path = pathlib.PosixPath(args.path)
+    if path.exists() and not args.force:
+        raise ConfigurationError(f"'{path}' already exists; pass --force to replace it")
+    if args.dry_run:
+        print(f"Would write a new configuration to '{path}'")
+        return {"path": str(path)}
+    try:
+        with open(path, "wt") as out_file:
+            out_file.write(text)
+    except OSError as e:
+        raise os_error(ConfigurationError, f"Unable to write configuration '{path}'", e)
+    if not args.json:
+        print(f"Wrote '{path}'; check it with: {pathlib.PosixPath(sys.argv[0]).name} --config {path} check-config")
+    return {"path": str(path)}
+
+
+# keys JSON configurations commonly use for comments, carried over as real comments by migrate-config
+COMMENT_KEYS = ("//", "_comment", "comment")
+
+
+def toml_value(value) -> str:
+    if type(value) is bool:
+        return "true" if value else "false"
+    if type(value) in (int, float):
+        return repr(value)
+    if type(value) is str:
+        return json.dumps(value)  # JSON strings are valid TOML basic strings
+    if type(value) is list:
+        return f"[{', '.join(toml_value(item) for item in value)}]"
+    raise ValueError(f"{value!r} has no TOML equivalent")
+
+
+def toml_key(key: str) -> str:
+    return key if re.fullmatch(r"[A-Za-z0-9_-]+", key) else json.dumps(key)
+
+
+def comment_lines(table: dict) -> list:
+    # remove any comment keys from a table, returning them as TOML comments
+    lines = []
+    for key in COMMENT_KEYS:
+        comment = table.pop(key, None)
+        for text in comment if type(comment) is list else [comment] if comment is not None else []:
+            lines += [f"# {line}".rstrip() for line in str(text).splitlines()]
+    return lines
+
+
+def toml_lines(table: dict, keys: tuple = ()) -> list:
+    # a table's own values under its header, then each of its sub-tables
+    comments = comment_lines(table)
+    values = [(key, value) for key, value in table.items() if type(value) is not dict]
+    tables = [(key, value) for key, value in table.items() if type(value) is dict]
+    lines = []
+    if keys and (comments or values or not tables):
+        lines += ["", f"[{'.'.join(toml_key(key) for key in keys)}]"]
+    lines += comments
+    lines += [f"{toml_key(key)} = {toml_value(value)}" for key, value in values]
+    for key, value in tables:
+        lines += toml_lines(value, keys + (key,))
+    return lines
+
+
+def migrated_configuration(configuration: dict, source) -> str:
+    # the configuration as TOML, with a lone device's settings moved under 'devices'
+    configuration = copy.deepcopy(configuration)
+    header = [
+        f"# s76-kbd-led-statemgr configuration, converted from '{source}' by 'migrate-config' on "
+        f"{datetime.date.today().isoformat()}.",
+    ]
+    comments = comment_lines(configuration)
+    if comments:
+        header += ["#", *comments]
+    if "devices" not in configuration and type(configuration.get("brightness")) is dict:
+        name = pathlib.PosixPath(configuration["brightness"].get("path", "")).parent.name or "keyboard"
+        device = {key: configuration.pop(key) for key in KNOWN_SETTINGS["device"] if key in configuration}
+        configuration["devices"] = {name: device}
+    configuration = {"version": configuration.pop("version", CONFIGURATION_VERSION), **configuration}
+    return "\n".join(header + toml_lines(configuration)) + "\n"
+
+
+def do_migrate_config(configuration: dict, args: argparse.Namespace) -> dict:
+    source = configuration_file(args.config)
+    if not source.exists():
+        raise ConfigurationError(f"There is no configuration at '{source}' to convert")
+    try:
+        document = load_configuration_file(source)
+    except OSError as e:
+        raise os_error(ConfigurationError, f"Unable to read configuration '{source}'", e)
+    except ValueError as e:
+        raise ConfigurationError(f"Unable to parse configuration '{source}': {e}")
+    if type(document) is not dict:
+        raise ConfigurationError(f"'{source}' does not contain a configuration object")
+    try:
+        text = migrated_configuration(document, source)
+    except ValueError as e:
+        raise ConfigurationError(f"Unable to convert '{source}': {e}")
+    if args.path == "-":
+        sys.stdout.write(text)
+        return {"source": str(source), "path": None}
+    path = pathlib.PosixPath(args.path) if args.path is not None else source.with_suffix(".toml")
+    if path.exists() and not args.force:
+        raise ConfigurationError(f"'{path}' already exists; pass --force to replace it")
+    if args.dry_run:
+        print(f"Would write the converted configuration to '{path}'")
+        return {"source": str(source), "path": str(path)}
+    try:
+        with open(path, "wt") as out_file:
+            out_file.write(text)
+    except OSError as e:
+        raise os_error(ConfigurationError, f"Unable to write configuration '{path}'", e)
+    if not args.json:
+        print(f"Wrote '{path}'; check it with: {pathlib.PosixPath(sys.argv[0]).name} --config {path} check-config")
+    return {"source": str(source), "path": str(path)}
+
+
+def do_list_devices(configuration: dict, args: argparse.Namespace) -> dict:
+    devices = discover_devices(pathlib.PosixPath(args.path))
+    if not args.json:
+        if not devices:
+            print(f"No *kbd_backlight* devices found in {args.path}")
+        for device in devices:
+            print(device["name"])
+            print(f"  path:           {device['path']}")
+            print(f"  driver:         {device['driver'] or 'unknown'}")
+            print(f"  parent:         {device['parent'] or 'unknown'}")
+            print(f"  attributes:     {', '.join(device['attributes']) or 'none known'}")
+            print(f"  max_brightness: {device['max_brightness'] if device['max_brightness'] is not None else 'unknown'}")
+    return {"devices": devices}
+
+
+def do_doctor(configuration: dict, args: argparse.Namespace) -> dict:
+    findings = []
+
+    def report(level: str, message: str, hint: str = None) -> None:
+        findings.append({"level": level, "message": message, "hint": hint})
+
+    # the kernel side only matters to devices driven through sysfs
+    uses_sysfs = any(
+        device.get("backend", configuration.get("backend", "sysfs")) == SysfsBackend.name
+        for device in device_tables(configuration).values()
+    )
+    devices = discover_devices(LEDS_PATH)
+    loaded = [module for module in DRIVER_MODULES if pathlib.PosixPath("/sys/module", module).exists()]
+    if loaded:
+        report("ok", f"Kernel module loaded: {', '.join(loaded)}")
+    elif devices:
+        # e.g. an ASUS, Dell or ThinkPad keyboard backlight, which is managed all the same
+        report("ok", "No System76 kernel module is loaded, but other drivers provide keyboard backlights")
+    elif uses_sysfs:
+        report(
+            "fail",
+            f"None of the kernel modules {', '.join(DRIVER_MODULES)} are loaded",
+            "Install the System76 DKMS/driver package for your distribution and run 'modprobe system76_acpi'",
+        )
+
+    for device in devices:
+        attributes = ", ".join(device["attributes"]) or "no known attributes"
+        driver = f" (driver {device['driver']})" if device["driver"] else ""
+        report("ok", f"LED device {device['name']}{driver} provides {attributes}")
+    if not devices and uses_sysfs:
+        report("fail", f"No *kbd_backlight* devices found in {LEDS_PATH}", "Check that the driver supports this model")
+
+    problems = validate_configuration(configuration, check_paths=False)
+    for problem in problems:
+        report("fail", f"Configuration: {problem}", "See 'check-config'")
+    for device in [] if problems else configured_devices(configuration).values():
+        for level, message, hint in device_backend(device).check(device):
+            report(level, message, hint)
+
+    if not problems:
+        state_directory = pathlib.PosixPath(configuration["state_path"]).parent
+        existing = state_directory
+        while not existing.exists():
+            existing = existing.parent
+        if not os.access(existing, os.W_OK):
+            report("fail", f"State directory {state_directory} is not writable", "Run as root, or use --state-file")
+        elif existing != state_directory:
+            report("ok", f"State directory {state_directory} does not exist yet, but can be created")
+        else:
+            report("ok", f"State directory {state_directory} is writable")
+        if load_state_file(configuration) is None:
+            report("warn", f"No readable state in {configuration['state_path']}", "It is written by the next 'save'")
+
+    if not args.json:
+        labels = {"ok": " ok ", "warn": "warn", "fail": "FAIL"}
+        for finding in findings:
+            print(f"[{labels[finding['level']]}] {finding['message']}")
+            if finding["hint"]:
+                print(f"       -> {finding['hint']}")
+    failures = [finding["message"] for finding in findings if finding["level"] == "fail"]
+    if failures:
+        raise ValidationError(
+            f"{len(failures)} problem(s) found", failures, reported=not args.json, exit_code=EXIT_FAILURE
+        )
+    return {"findings": findings}
+
+
+def flatten_settings(settings: dict, prefix: str = "") -> dict:
+    flat = {}
+    for key, value in settings.items():
+        if type(value) is dict:
+            flat.update(flatten_settings(value, f"{prefix}{key}."))
+        else:
+            flat[f"{prefix}{key}"] = value
+    return flat
+
+
+def reload_configuration(configuration: dict, args: argparse.Namespace) -> dict:
+    # re-read the configuration for the same device, keeping the current one if the new one is unusable
+    try:
+        reloaded = select_device(configuration_for(args), configuration["device"])
+    except StatemgrError as e:
+        logger.error(f"Keeping the current configuration, as reloading it failed: {e}")
+        for problem in getattr(e, "problems", []):
+            logger.error(f"problem: {problem}")
+        return configuration
+    before = flatten_settings(configuration)
+    after = flatten_settings(reloaded)
+    changed = sorted(key for key in before.keys() | after.keys() if before.get(key) != after.get(key))
+    for key in changed:
+        logger.warning(f"Configuration reloaded: {key} {before.get(key)!r} -> {after.get(key)!r}")
+    if not changed:
+        logger.warning("Configuration reloaded, nothing changed")
+    return reloaded
+
+
+def do_watch(configuration: dict, args: argparse.Namespace) -> dict:
+    # sysfs attributes mostly don't support inotify, so poll them
+    previous = {}
+    changes = 0
+    hangups = []
+    signal.signal(signal.SIGHUP, lambda signum, frame: hangups.append(signum))
+    try:
+        while True:
+            if hangups:
+                hangups.clear()
+                configuration = reload_configuration(configuration, args)
+            for key in ("brightness", "color") if color_enabled(configuration) else ("brightness",):
+                try:
+                    if not device_backend(configuration).uses_paths:
+                        value = read_hardware(configuration)[key]
+                    elif key == "color":
+                        value = hardware_color(configuration, read_attribute(configuration["color"]["path"]))
+                    else:
+                        value = read_attribute(configuration["brightness"]["path"])
+                except StatemgrError as e:
+                    value = f"unavailable ({e})"
+                if previous.get(key) == value:
+                    continue
+                timestamp = datetime.datetime.now().isoformat(timespec="milliseconds")
+                if args.json:
+                    event = {"time": timestamp, "attribute": key, "old": previous.get(key), "new": value}
+                    print(json.dumps(event), file=sys.__stdout__, flush=True)
+                elif key in previous:
+                    print(f"{timestamp} {key}: {previous[key]} -> {value}", flush=True)
+                else:
+                    print(f"{timestamp} {key}: {value}", flush=True)
+                changes += key in previous
+                previous[key] = value
+            time.sleep(args.interval)
+    except KeyboardInterrupt:
+        pass
+    return {"changes": changes, "last": previous}
+
+
+def do_track(configuration: dict, args: argparse.Namespace) -> dict:
+    # like 'watch', but saving each change made to the keyboard (e.g. with the Fn keys) once it settles, so the
+    # state is current even when 'pre' never gets to run, as on a crash or power loss
+    baseline = None
+    pending = None
+    changed_at = 0
+    saves = 0
+    hangups = []
+    signal.signal(signal.SIGHUP, lambda signum, frame: hangups.append(signum))
+    # the monotonic clock stops during suspend and the boot-time one doesn't, so a growing gap means a resume
+    slept = time.clock_gettime(time.CLOCK_BOOTTIME) - time.monotonic()
+    resumed_at = None
+    notifier = hardware_change_notifier(configuration)
+    # set when the driver reports a change of its own, which needs no settling
+    hardware_changed = False
+    try:
+        while True:
+            if hangups:
+                hangups.clear()
+                configuration = reload_configuration(configuration, args)
+                close_notifier(notifier)
+                notifier = hardware_change_notifier(configuration)
+            now = time.monotonic()
+            if time.clock_gettime(time.CLOCK_BOOTTIME) - now > slept + 1:
+                # whatever the firmware shows on resume is not the user's doing, and 'post' is about to restore
+                slept = time.clock_gettime(time.CLOCK_BOOTTIME) - now
+                logger.info(f"Resumed from sleep, not tracking for {TRACK_RESUME_GRACE} s while the state is restored")
+                resumed_at = now
+                baseline = pending = None
+            if resumed_at is not None and now - resumed_at < TRACK_RESUME_GRACE:
+                time.sleep(args.interval)
+                continue
+            try:
+                current = read_hardware(configuration)
+            except StatemgrError as e:
+                logger.debug(f"Unable to read the keyboard, trying again: {e}")
+                time.sleep(args.interval)
+                continue
+            if baseline is None:
+                baseline = current
+            elif current == baseline:
+                pending = None
+            elif hardware_changed:
+                logger.info(f"Brightness changed to '{current['brightness']}' by the keyboard, saving")
+                write_state(configuration, copy.deepcopy(current), "track", dry_run=args.dry_run)
+                baseline = current
+                pending = None
+                saves += 1
+            elif current != pending:
+                # waiting for it to settle, rather than saving every step of holding down a brightness key
+                pending = current
+                changed_at = now
+            elif now - changed_at >= args.settle:
+                logger.info(f"Brightness '{current['brightness']}' and color '{current['color']}' changed, saving")
+                write_state(configuration, copy.deepcopy(current), "track", dry_run=args.dry_run)
+                baseline = current
+                pending = None
+                saves += 1
+            hardware_changed = wait_for_hardware_change(notifier, args.interval)
+    except KeyboardInterrupt:
+        pass
+    finally:
+        close_notifier(notifier)
+    return {"saves": saves, "last": baseline}
+
+
+def hardware_change_notifier(configuration: dict):
+    # drivers that change the brightness themselves, as on the Fn keys, say so through brightness_hw_changed, which
+    # can be polled for; None where there is no such attribute
+    if not device_backend(configuration).uses_paths:
+        return None
+    path = pathlib.PosixPath(configuration["brightness"]["path"]).with_name("brightness_hw_changed")
+    try:
+        descriptor = os.open(path, os.O_RDONLY)
+    except OSError:
+        return None
+    poller = select.poll()
+    poller.register(descriptor, select.POLLPRI | select.POLLERR)
+    # sysfs only notifies pollers once the attribute has been read
+    read_notified(descriptor)
+    logger.debug(f"Polling '{path}' for changes made by the keyboard")
+    return descriptor, poller
+
+
+def read_notified(descriptor: int):
+    try:
+        os.pread(descriptor, 64, 0)
+    except OSError:
+        # ENODATA until the first change
+        pass
+
+
+def wait_for_hardware_change(notifier, timeout: float) -> bool:
+    # waits up to 'timeout' seconds, returning early and true when the driver reports a change
+    if notifier is None:
+        time.sleep(timeout)
+        return False
+    descriptor, poller = notifier
+    if not poller.poll(timeout * 1000):
+        return False
+    read_notified(descriptor)
+    return True
+
+
+def close_notifier(notifier):
+    if notifier is not None:
+        os.close(notifier[0])
+
+
+def do_status(configuration: dict, args: argparse.Namespace) -> dict:
+    # noinspection PyBroadException
+    try:
+        hardware = read_hardware(configuration)
+    except Exception as e:
+        hardware = {}
+        logger.warning(f"Unable to read hardware state: {e}")
+    if not color_enabled(configuration):
+        hardware.pop("color", None)
+    saved = load_state_file(configuration)
+    status = {
+        "device": configuration["device"],
+        "hardware": hardware,
+        "saved": saved,
+        "default": {key: configuration[key]["default"] for key in ("brightness", "color")},
+        "zones": {
+            zone: {
+                "hardware": hardware.get("zones", {}).get(zone),
+                "saved": ((saved or {}).get("zones") or {}).get(zone),
+                "default": settings["default"],
+            }
+            for zone, settings in configuration.get("zones", {}).items()
+        },
+        "drifted": [
+            key for key in ("brightness", "color")
+            if saved and key in hardware and key in saved and hardware[key] != str(saved[key])
+        ],
+        "paths": dict(device_backend(configuration).paths(configuration), state=configuration["state_path"]),
+    }
+    if args.json:
+        return status
+    print(f"device: {status['device']}")
+    print()
+    print(f"{'':<12}{'hardware':<10}{'saved':<10}default")
+    for key in ("brightness", "color"):
+        current = hardware.get(key, "-")
+        persisted = str((saved or {}).get(key, "-"))
+        drift = "  (drifted)" if key in status["drifted"] else ""
+        print(f"{key:<12}{current:<10}{persisted:<10}{status['default'][key]}{drift}")
+    for zone, values in status["zones"].items():
+        current = values["hardware"] or "-"
+        persisted = str(values["saved"] or "-")
+        print(f"{zone + ' zone':<12}{current:<10}{persisted:<10}{values['default']}")
+    print()
+    print(f"brightness path: {status['paths']['brightness']}")
+    print(f"color path:      {status['paths']['color'] or '(not managed)'}")
+    print(f"state path:      {status['paths']['state']}" + ("" if saved else " (missing or unreadable)"))
+    if saved and type(saved.get("saved_at")) is str:
+        origin = " ".join(
+            f"{label} {saved[key]}"
+            for label, key in (("by", "saved_by"), ("on", "hostname"))
+            if type(saved.get(key)) is str
+        )
+        print(f"saved:           {saved['saved_at']} {origin}".rstrip())
+    if saved and saved.get("enabled") is False and type(saved.get("last_brightness")) is str:
+        print(f"saved off, to come back on at brightness {saved['last_brightness']}")
+    return status
+
+
+def do_diff(configuration: dict, args: argparse.Namespace) -> dict:
+    # for scripts deciding whether to restore: exits non-zero when the keyboard differs from what would be restored
+    if load_state_file(configuration) is None:
+        raise StateError(f"No readable state in '{configuration['state_path']}'")
+    saved = read_state(configuration)
+    hardware = read_hardware(configuration)
+    values = [("brightness", saved["brightness"], hardware["brightness"])]
+    if color_enabled(configuration):
+        values.append(("color", saved["color"], hardware["color"]))
+    values += [
+        (f"{zone} zone", color, hardware.get("zones", {}).get(zone)) for zone, color in saved.get("zones", {}).items()
+    ]
+    differences = [
+        {"attribute": attribute, "saved": saved_value, "hardware": hardware_value}
+        for attribute, saved_value, hardware_value in values
+        if saved_value != hardware_value
+    ]
+    if not args.json and differences:
+        print(f"--- saved ({configuration['state_path']})")
+        print(f"+++ hardware ({configuration['device']})")
+        for difference in differences:
+            print(f"-{difference['attribute']} {difference['saved']}")
+            print(f"+{difference['attribute']} {difference['hardware']}")
+    if differences:
+        raise ValidationError(
+            f"{len(differences)} difference(s) between the saved state and the hardware",
+            [f"{d['attribute']}: saved {d['saved']}, hardware {d['hardware']}" for d in differences],
+            reported=True,
+            exit_code=EXIT_FAILURE,
+        )
+    return {"differences": differences}
+
+
+def describe_options(parser: argparse.ArgumentParser) -> tuple:
+    options = []
+    positionals = []
+    for action in parser._actions:
+        if action.help == argparse.SUPPRESS:
+            continue
+        if isinstance(action, argparse._SubParsersAction):
+            # nested commands are completed and documented as a positional argument
+            positionals.append({
+                "flags": [],
+                "help": ", ".join(f"{choice.dest}: {choice.help}" for choice in action._choices_actions),
+                "takes_value": True,
+                "repeatable": False,
+                "choices": [choice.dest for choice in action._choices_actions],
+                "path": False,
+                "metavar": action.metavar or action.dest,
+            })
+            continue
+        entry = {
+            "flags": list(action.option_strings),
+            "help": (action.help or "").replace("%%", "%"),
+            "takes_value": action.nargs != 0,
+            "repeatable": isinstance(action, argparse._CountAction),
+            "choices": list(action.choices) if action.choices is not None else None,
+            "path": action.metavar == "PATH",
+            "metavar": action.metavar or (action.dest.upper() if action.option_strings else action.dest),
+        }
+        (options if action.option_strings else positionals).append(entry)
+    return options, positionals
+
+
+def describe_cli(parser: argparse.ArgumentParser) -> dict:
+    options, positionals = describe_options(parser)
+    commands = []
+    for action in parser._actions:
+        if not isinstance(action, argparse._SubParsersAction):
+            continue
+        for choice_action in action._choices_actions:
+            subparser = action.choices[choice_action.dest]
+            sub_options, sub_positionals = describe_options(subparser)
+            commands.append({
+                "names": [name for name, candidate in action.choices.items() if candidate is subparser],
+                "help": choice_action.help or "",
+                "description": subparser.description or "",
+                "usage": subparser.format_usage().replace("usage: ", "", 1).strip(),
+                "options": sub_options,
+                "positionals": sub_positionals,
+            })
+    return {
+        "prog": parser.prog,
+        "description": parser.description or "",
+        "options": options,
+        "positionals": positionals,
+        "commands": commands,
+    }
+
+
+def bash_completion(cli: dict) -> str:
+    function = "_" + re.sub(r"\W", "_", cli["prog"])
+
+    def words(options: list, positionals: list) -> str:
+        result = [flag for option in options for flag in option["flags"]]
+        for positional in positionals:
+            result.extend(positional["choices"] or [])
+        return " ".join(result)
+
+    value_options = [option for option in cli["options"] if option["takes_value"]]
+    value_options += [option for command in cli["commands"] for option in command["options"] if option["takes_value"]]
+    lines = [
+        f"# bash completion for {cli['prog']}",
+        f"{function}() {{",
+        '    local cur="${COMP_WORDS[COMP_CWORD]}" command="" word',
+        '    case "${COMP_WORDS[COMP_CWORD-1]}" in',
+    ]
+    for option in value_options:
+        if option["choices"]:
+            reply = f'COMPREPLY=($(compgen -W "{" ".join(option["choices"])}" -- "$cur"))'
+        elif option["path"]:
+            reply = 'COMPREPLY=($(compgen -f -- "$cur"))'
+        else:
+            reply = "COMPREPLY=()"
+        lines += [f"        {'|'.join(option['flags'])})", f"            {reply}"]
+        lines += ["            return", "            ;;"]
+    lines += [
+        "    esac",
+        '    for word in "${COMP_WORDS[@]:1:COMP_CWORD-1}"; do',
+        '        case "$word" in',
+        f"            {'|'.join(name for command in cli['commands'] for name in command['names'])})",
+        '                command="$word"',
+        "                break",
+        "                ;;",
+        "        esac",
+        "    done",
+        '    case "$command" in',
+        '        "")',
+        f'            COMPREPLY=($(compgen -W "{words(cli["options"], [])} '
+        f'{" ".join(name for command in cli["commands"] for name in command["names"])}" -- "$cur"))',
+        "            ;;",
+    ]
+    for command in cli["commands"]:
+        lines += [
+            f"        {'|'.join(command['names'])})",
+            f'            COMPREPLY=($(compgen -W "{words(command["options"], command["positionals"])}" -- "$cur"))',
+            "            ;;",
+        ]
+    lines += [
+        "    esac",
+        "}",
+        f"complete -o default -F {function} {cli['prog']}",
+    ]
+    return "\n".join(lines) + "\n"
+
+
+def zsh_completion(cli: dict) -> str:
+    function = "_" + re.sub(r"\W", "_", cli["prog"])
+
+    def quote(text: str) -> str:
+        return text.replace("'", "'\\''").replace("[", "(").replace("]", ")").replace(":", "\\:")
+
+    def specs(options: list, positionals: list) -> list:
+        result = []
+        for option in options:
+            repeat = "*" if option["repeatable"] else ""
+            if option["choices"]:
+                value = f":{option['metavar']}:({' '.join(option['choices'])})"
+            elif option["path"]:
+                value = f":{option['metavar']}:_files"
+            elif option["takes_value"]:
+                value = f":{option['metavar']}:"
+            else:
+                value = ""
+            for flag in option["flags"]:
+                result.append(f"'{repeat}{flag}[{quote(option['help'])}]{value}'")
+        for index, positional in enumerate(positionals, start=1):
+            values = f"({' '.join(positional['choices'])})" if positional["choices"] else ""
+            result.append(f"'{index}:{positional['metavar']}:{values}'")
+        return result
+
+    lines = [
+        f"#compdef {cli['prog']}",
+        "",
+        f"{function}() {{",
+        "    local -a commands",
+        "    commands=(",
+    ]
+    for command in cli["commands"]:
+        for name in command["names"]:
+            lines.append(f"        '{name}:{quote(command['help'])}'")
+    lines += ["    )", "    _arguments -C \\"]
+    lines += [f"        {spec} \\" for spec in specs(cli["options"], [])]
+    lines += [
+        "        '1: :->command' \\",
+        "        '*:: :->args'",
+        '    case "$state" in',
+        "        command)",
+        "            _describe 'command' commands",
+        "            ;;",
+        "        args)",
+        '            case "$words[1]" in',
+    ]
+    for command in cli["commands"]:
+        command_specs = specs(command["options"], command["positionals"])
+        lines.append(f"                {'|'.join(command['names'])})")
+        if command_specs:
+            lines.append(f"                    _arguments {' '.join(command_specs)}")
+        lines.append("                    ;;")
+    lines += [
+        "            esac",
+        "            ;;",
+        "    esac",
+        "}",
+        "",
+        f'{function} "$@"',
+    ]
+    return "\n".join(lines) + "\n"
+
+
+def fish_completion(cli: dict) -> str:
+    prog = cli["prog"]
+
+    def quote(text: str) -> str:
+        return "'" + text.replace("\\", "\\\\").replace("'", "\\'") + "'"
+
+    def option_lines(options: list, condition: str) -> list:
+        result = []
+        for option in options:
+            line = f"complete -c {prog} -n {quote(condition)}"
+            for flag in option["flags"]:
+                line += f" -s {flag[1:]}" if len(flag) == 2 else f" -l {flag[2:]}"
+            if option["choices"]:
+                line += f" -x -a {quote(' '.join(option['choices']))}"
+            elif option["path"]:
+                line += " -r -F"
+            elif option["takes_value"]:
+                line += " -x"
+            result.append(line + f" -d {quote(option['help'])}")
+        return result
+
+    lines = [f"# fish completion for {prog}", f"complete -c {prog} -f"]
+    lines += option_lines(cli["options"], "__fish_use_subcommand")
+    for command in cli["commands"]:
+        for name in command["names"]:
+            lines.append(f"complete -c {prog} -n '__fish_use_subcommand' -a {name} -d {quote(command['help'])}")
+    for command in cli["commands"]:
+        condition = f"__fish_seen_subcommand_from {' '.join(command['names'])}"
+        lines += option_lines(command["options"], condition)
+        for positional in command["positionals"]:
+            if positional["choices"]:
+                lines.append(f"complete -c {prog} -n {quote(condition)} -a {quote(' '.join(positional['choices']))}")
+    return "\n".join(lines) + "\n"
+
+
+def man_page(cli: dict) -> str:
+    def escape(text: str) -> str:
+        text = text.replace("\\", "\\e").replace("-", "\\-")
+        return "\\&" + text if text.startswith((".", "'")) else text
+
+    def option_entries(options: list, positionals: list) -> list:
+        result = []
+        for option in options:
+            flags = ", ".join(f"\\fB{escape(flag)}\\fR" for flag in option["flags"])
+            if option["takes_value"]:
+                flags += f" \\fI{escape(option['metavar'])}\\fR"
+            result += [".TP", flags, escape(option["help"])]
+        for positional in positionals:
+            result += [".TP", f"\\fI{escape(positional['metavar'])}\\fR"]
+            help_text = positional["help"]
+            if positional["choices"]:
+                help_text += f" (one of: {', '.join(positional['choices'])})"
+            result.append(escape(help_text))
+        return result
+
+    prog = escape(cli["prog"])
+    lines = [
+        f'.TH "{cli["prog"].upper()}" 8 "" "" "System Administration"',
+        ".SH NAME",
+        f"{prog} \\- {escape(cli['description'])}",
+        ".SH SYNOPSIS",
+        f".B {prog}",
+        "[\\fIOPTIONS\\fR] \\fICOMMAND\\fR [\\fICOMMAND OPTIONS\\fR]",
+        ".SH DESCRIPTION",
+        escape(cli["description"]),
+        ".SH OPTIONS",
+        "Options must be given before the command.",
+    ]
+    lines += option_entries(cli["options"], [])
+    lines.append(".SH COMMANDS")
+    for command in cli["commands"]:
+        lines += [f'.SS "{escape(", ".join(command["names"]))}"', escape(command["description"] or command["help"])]
+        lines += [".PP", f"Usage: \\fB{escape(command['usage'])}\\fR"]
+        lines += option_entries(
+            [option for option in command["options"] if "--help" not in option["flags"]],
+            command["positionals"],
+        )
+    lines += [".SH FILES"]
+    lines += [
+        ".TP",
+        "\\fI$XDG_CONFIG_HOME/s76-kbd-led-statemgr/config.toml\\fR (or \\fI.yaml\\fR, \\fI.json\\fR)",
+        "Per-user configuration, tried first when not running as root.",
+    ]
+    for config_path in CONFIGURATION_PATHS:
+        lines += [".TP", f"\\fI{escape(str(config_path))}\\fR", "Configuration, in order of preference."]
+    lines += [
+        ".TP",
+        f"\\fI{escape(str(DROP_IN_PATH))}/*.toml\\fR (or \\fI.yaml\\fR, \\fI.json\\fR)",
+        "Drop-in configuration, merged over the above in lexical order.",
+    ]
+    lines += [
+        ".TP",
+        f"\\fI{escape(DEFAULT_CONFIGURATION['state_path'])}\\fR",
+        "Default location of the saved state.",
+        ".SH EXIT STATUS",
+    ]
+    for code, meaning in (
+        (0, "Success."),
+        (EXIT_FAILURE, "Any other failure, including problems found by doctor."),
+        (EXIT_USAGE, "Bad arguments."),
+        (EXIT_CONFIGURATION, "The configuration could not be read, or is invalid."),
+        (EXIT_STATE, "The state file could not be read or written."),
+        (EXIT_DEVICE, "A sysfs attribute could not be read or written."),
+        (EXIT_PERMISSION, "Insufficient permissions."),
+    ):
+        lines += [".TP", f"\\fB{code}\\fR", meaning]
+    lines += [
+        ".SH SEE ALSO",
+        ".BR systemd\\-suspend.service (8)",
+    ]
+    return "\n".join(lines) + "\n"
+
+
+def hook_files(configuration: dict, args: argparse.Namespace) -> dict:
+    script = pathlib.PosixPath(__file__).resolve()
+    command = [str(script)]
+    # bake in any overrides given to install-hooks, so the hooks act on the same files
+    if args.config is not None:
+        command += ["--config", str(pathlib.PosixPath(args.config).resolve())]
+    if args.state_file is not None:
+        command += ["--state-file", str(pathlib.PosixPath(args.state_file).resolve())]
+    command = shlex.join(command)
+    state_directory = pathlib.PosixPath(configuration["state_path"]).parent
+    files = {
+        pathlib.PosixPath(args.hook_dir, HOOK_NAME): (0o755, "\n".join([
+            "#!/bin/sh",
+            HOOK_MARKER,
+            f'exec {command} "$@"',
+            "",
+        ])),
+    }
+    if not args.no_unit:
+        files[pathlib.PosixPath(args.unit_dir, f"{HOOK_NAME}.service")] = (0o644, "\n".join([
+            HOOK_MARKER,
+            "[Unit]",
+            "Description=Save and restore System76 keyboard backlight state across poweroff",
+            "Documentation=https://github.com/draeath/s76-kbd-led-statemgr/blob/master/README.md",
+            "After=local-fs.target",
+            "Requisite=local-fs.target",
+            f"RequiresMountsFor={script.parent}",
+            f"RequiresMountsFor={state_directory}",
+            "",
+            "[Service]",
+            "Type=oneshot",
+            "RemainAfterExit=yes",
+            f"ExecStart={command} post",
+            f"ExecStop={command} pre",
+            "Restart=no",
+            "",
+            "[Install]",
+            "WantedBy=basic.target",
+            "",
+        ]))
+    return files
+
+
+def is_own_hook(path: pathlib.PosixPath) -> bool:
+    # noinspection PyBroadException
+    try:
+        with open(path, "rt") as hook_file:
+            return HOOK_MARKER in hook_file.read(4096).splitlines()
+    except Exception:
+        return False
+
+
+def do_install_hooks(configuration: dict, args: argparse.Namespace) -> dict:
+    files = hook_files(configuration, args)
+    for path in files:
+        if (path.exists() or path.is_symlink()) and not is_own_hook(path) and not args.force:
+            raise StatemgrError(f"Refusing to overwrite '{path}', which was not installed by this tool (see --force)")
+    for path, (mode, contents) in files.items():
+        if args.dry_run:
+            print(f"Would write '{path}':")
+            print(contents)
+            continue
+        path.parent.mkdir(parents=True, exist_ok=True)
+        if path.is_symlink():
+            path.unlink()
+        with open(path, "wt") as hook_file:
+            hook_file.write(contents)
+        path.chmod(mode)
+        logger.info(f"Installed '{path}'")
+    if not args.no_unit and not args.json:
+        print(f"Now run: systemctl daemon-reload && systemctl enable --now {HOOK_NAME}.service")
+    return {"installed": [str(path) for path in files]}
+
+
+def do_uninstall_hooks(configuration: dict, args: argparse.Namespace) -> dict:
+    unit_path = pathlib.PosixPath(args.unit_dir, f"{HOOK_NAME}.service")
+    paths = [pathlib.PosixPath(args.hook_dir, HOOK_NAME)]
+    if is_own_hook(unit_path):
+        # the symlinks 'systemctl enable' made, which would otherwise be left dangling
+        paths += [
+            link for link in pathlib.PosixPath(args.unit_dir).glob(f"*.wants/{HOOK_NAME}.service")
+            if link.is_symlink() and link.resolve() == unit_path.resolve()
+        ]
+    paths.append(unit_path)
+    removed = []
+    foreign = []
+    for path in paths:
+        if not (path.exists() or path.is_symlink()):
+            logger.info(f"'{path}' is not installed")
+        elif not is_own_hook(path):
+            foreign.append(str(path))
+        elif args.dry_run:
+            print(f"Would remove '{path}'")
+            removed.append(str(path))
+        else:
+            path.unlink()
+            logger.info(f"Removed '{path}'")
+            removed.append(str(path))
+    if foreign:
+        raise StatemgrError(f"Left {', '.join(foreign)} in place, as it was not installed by this tool")
+    if removed and not args.json:
+        print("Now run: systemctl daemon-reload")
+    return {"removed": removed}
+
+
+def features() -> dict:
+    return {
+        "tui": curses is not None,
+        "toml": tomllib is not None,
+        "yaml": yaml is not None,
+    }
+
+
+def git_commit():
+    # only available when running from a git checkout
+    try:
+        result = subprocess.run(
+            ["git", "-C", str(pathlib.PosixPath(__file__).resolve().parent), "rev-parse", "--short", "HEAD"],
+            capture_output=True,
+            text=True,
+            timeout=5,
+        )
+    except (OSError, subprocess.SubprocessError):
+        return None
+    return result.stdout.strip() if result.returncode == 0 else None
+
+
+def do_version(configuration: dict, args: argparse.Namespace) -> dict:
+    information = {"version": __version__}
+    if args.verbose_version:
+        information.update({
+            "python": f"{platform.python_implementation()} {platform.python_version()}",
+            "platform": f"{platform.machine()}-{platform.system().lower()}",
+            "kernel": platform.release(),
+            "git_commit": git_commit(),
+            "script": str(pathlib.PosixPath(__file__).resolve()),
+            "features": features(),
+            "configuration_paths": [str(path) for path in configuration_paths()],
+            "default_brightness_path": DEFAULT_CONFIGURATION["brightness"]["path"],
+            "default_color_path": DEFAULT_CONFIGURATION["color"]["path"],
+            "default_state_path": DEFAULT_CONFIGURATION["state_path"],
+            "leds_path": str(LEDS_PATH),
+            "system_sleep_path": str(SYSTEM_SLEEP_PATH),
+            "unit_path": str(UNIT_PATH),
+        })
+    if not args.json:
+        print(f"{pathlib.PosixPath(sys.argv[0]).name} {__version__}")
+        for key, value in information.items():
+            if key == "version":
+                continue
+            if type(value) is dict:
+                value = ", ".join(f"{name} {'enabled' if enabled else 'disabled'}" for name, enabled in value.items())
+            elif type(value) is list:
+                value = ", ".join(value)
+            print(f"{key}: {value if value is not None else 'unknown'}")
+    return information
+
+
+def do_generate_man(configuration: dict, args: argparse.Namespace) -> dict:
+    page = man_page(describe_cli(build_parser()))
+    if not args.json:
+        print(page, end="")
+    return {"man": page}
+
+
+def do_completions(configuration: dict, args: argparse.Namespace) -> dict:
+    generators = {"bash": bash_completion, "zsh": zsh_completion, "fish": fish_completion}
+    script = generators[args.shell](describe_cli(build_parser()))
+    if not args.json:
+        print(script, end="")
+    return {"shell": args.shell, "script": script}
+
+
+def brightness_argument(value: str) -> str:
+    if not valid_brightness_setting(value):
+        raise argparse.ArgumentTypeError(f"'{value}' is not an integer between 0 and 255, or a percentage")
+    return value
+
+
+def time_argument(value: str) -> str:
+    at = schedule_time(value)
+    if at is None:
+        raise argparse.ArgumentTypeError(f"'{value}' is not a time of day in HH:MM form")
+    return at
+
+
+def positive_int_argument(value: str) -> int:
+    if not value.isdigit() or int(value) == 0:
+        raise argparse.ArgumentTypeError(f"'{value}' is not a positive integer")
+    return int(value)
+
+
+def non_negative_int_argument(value: str) -> int:
+    if not value.isdigit():
+        raise argparse.ArgumentTypeError(f"'{value}' is not a number")
+    return int(value)
+
+
+def snapshot_name_argument(value: str) -> str:
+    if re.fullmatch(r"[A-Za-z0-9][A-Za-z0-9_.-]*", value) is None:
+        raise argparse.ArgumentTypeError(f"'{value}' is not a snapshot name of letters, digits, '_', '.' and '-'")
+    return value
+
+
+def color_argument(value: str) -> str:
+    color = parse_color(value)
+    if not valid_color(color):
+        described = f"'{value}' ({color})" if color != value.lstrip("#").upper() else f"'{value}'"
+        raise argparse.ArgumentTypeError(f"{described} is not a supported color")
+    return color
+
+
+def zone_argument(value: str) -> tuple:
+    zone, separator, color = value.partition("=")
+    if not separator or re.fullmatch(r"[a-z]+", zone) is None:
+        raise argparse.ArgumentTypeError(f"'{value}' is not ZONE=COLOR, e.g. left=red")
+    return zone, color_argument(color)
+
+
+def build_parser() -> argparse.ArgumentParser:
+    parser = argparse.ArgumentParser(
+        description="Save and restore System76 keyboard backlight state across power states",
+        epilog=f"Exit status: 0 on success, {EXIT_USAGE} for bad arguments, {EXIT_CONFIGURATION} for configuration "
+        f"errors, {EXIT_STATE} for state file errors, {EXIT_DEVICE} for sysfs errors, {EXIT_PERMISSION} for "
+        f"insufficient permissions, and {EXIT_FAILURE} otherwise. "
+        "See https://github.com/draeath/s76-kbd-led-statemgr/blob/master/README.md",
+    )
+    parser.add_argument("--version", action="version", version=f"%(prog)s {__version__}")
+    parser.add_argument(
+        "-C",
+        "--config",
+        metavar="PATH",
+        help="Read configuration from PATH instead of the default locations",
+    )
+    parser.add_argument(
+        "--backend",
+        choices=list(BACKENDS),
+        help="Reach every device through BACKEND instead of the configured one, e.g. 'mock' to try things out",
+    )
+    parser.add_argument(
+        "-S",
+        "--state-file",
+        metavar="PATH",
+        help="Read and write state at PATH instead of the configured state_path",
+    )
+    parser.add_argument(
+        "-n",
+        "--dry-run",
+        action="store_true",
+        help="Print what would be written instead of writing to sysfs or the state file",
+    )
+    verbosity_group = parser.add_mutually_exclusive_group()
+    verbosity_group.add_argument(
+        "-v",
+        "--verbose",
+        action="count",
+        default=0,
+        help="Report what is loaded and applied; repeat for debugging and timing detail",
+    )
+    verbosity_group.add_argument(
+        "-q",
+        "--quiet",
+        action="store_true",
+        help="Only report errors",
+    )
+    parser.add_argument(
+        "--json",
+        action="store_true",
+        help="Print results and errors as JSON on stdout; other output goes to stderr",
+    )
+    parser.add_argument(
+        "--lenient",
+        action="store_true",
+        help="Skip configuration files that cannot be read and carry on despite invalid settings, "
+        "rather than stopping with an error",
+    )
+    subparsers = parser.add_subparsers(title="commands", dest="command", metavar="COMMAND", required=True)
+
+    save_parser = subparsers.add_parser(
+        "save",
+        aliases=["pre"],
+        help="Save the current keyboard backlight state",
+        description="Read brightness and color from sysfs and write them to the state file.",
+    )
+    save_parser.set_defaults(handler=do_pre, writes=True, scope="devices")
+
+    restore_parser = subparsers.add_parser(
+        "restore",
+        aliases=["post"],
+        help="Restore the saved keyboard backlight state",
+        description="Apply the state file (or configured defaults) to sysfs.",
+    )
+    restore_parser.add_argument(
+        "--from",
+        dest="history",
+        type=positive_int_argument,
+        metavar="N",
+        help="Restore history entry N (1 is the latest state replaced; see 'history list') instead",
+    )
+    restore_parser.add_argument(
+        "--wait-for-device",
+        dest="wait_for_device",
+        type=non_negative_int_argument,
+        metavar="MS",
+        help="Wait up to MS milliseconds for a missing LED device to appear first (default: wait_for_device_ms, or 0)",
+    )
+    restore_parser.set_defaults(handler=do_post, writes=True, scope="devices")
+
+    for transition_parser in (save_parser, restore_parser):
+        transition_parser.add_argument(
+            "sleep_operation",
+            nargs="?",
+            help="Ignored. The operation (suspend, hibernate, ...) passed along by systemd-sleep",
+        )
+        transition_parser.add_argument(
+            "--slot",
+            type=positive_int_argument,
+            metavar="N",
+            help="Use numbered state slot N, kept beside the state file, instead of the state file itself",
+        )
+
+    set_parser = subparsers.add_parser(
+        "set",
+        help="Set brightness and/or color, and save them",
+        description="Write brightness and/or color to sysfs and update the state file to match.",
+    )
+    set_parser.add_argument(
+        "-b",
+        "--brightness",
+        type=brightness_argument,
+        help="Brightness, 0-255 or a percentage of the device's max_brightness (e.g. 50%%)",
+    )
+    set_parser.add_argument("-c", "--color", type=color_argument, help="Color as RRGGBB hex, or a CSS color name")
+    set_parser.add_argument(
+        "-z",
+        "--zone",
+        action="append",
+        type=zone_argument,
+        metavar="ZONE=COLOR",
+        help="Color of one of the configured zones, e.g. left=red; may be repeated",
+    )
+    set_parser.add_argument(
+        "--stdin",
+        action="store_true",
+        help="Read the whole state, as JSON in the state file's format, from stdin (--brightness and --color "
+        "override it)",
+    )
+    set_parser.set_defaults(handler=do_set, writes=True)
+
+    history_parser = subparsers.add_parser(
+        "history",
+        help="List the states kept from earlier saves",
+        description="Saving keeps the state it replaces, up to the configuration's 'history' (default "
+        f"{DEFAULT_HISTORY}), for 'restore --from N' to go back to.",
+    )
+    history_subparsers = history_parser.add_subparsers(dest="action", metavar="ACTION", required=True)
+    history_list_parser = history_subparsers.add_parser("list", help="List the kept states, latest first")
+    history_parser.set_defaults(handler=do_history, writes=False)
+
+    undo_parser = subparsers.add_parser(
+        "undo",
+        help="Go back to the state saved before the last change",
+        description="Apply and save the state that the last change replaced. Running it again flips back.",
+    )
+    undo_parser.set_defaults(handler=do_undo, writes=True)
+
+    toggle_parser = subparsers.add_parser(
+        "toggle",
+        help="Turn the backlight off, or back on at its last brightness",
+        description="Set brightness to 0 if it is on, otherwise restore the last non-zero brightness.",
+    )
+    toggle_parser.set_defaults(handler=do_toggle, writes=True)
+
+    brightness_parser = subparsers.add_parser(
+        "brightness",
+        help="Step brightness up or down, and save it",
+        description="Raise or lower brightness by a step (the configured 'brightness.step', or 16), "
+        "clamped to the device's max_brightness. If 'brightness.levels' has been set up by 'calibrate', "
+        "move to the next of those levels instead.",
+    )
+    brightness_parser.add_argument("direction", choices=["up", "down"], help="Which way to step")
+    brightness_parser.add_argument(
+        "-s",
+        "--step",
+        type=positive_int_argument,
+        help="Step size, overriding the configuration (and any calibrated levels)",
+    )
+    brightness_parser.set_defaults(handler=do_brightness, writes=True)
+
+    color_parser = subparsers.add_parser(
+        "color",
+        help="Cycle through the color palette, and save it",
+        description="Switch to the next or previous color in the configured 'color.palette' "
+        "(by default red, yellow, green, cyan, blue, magenta, white).",
+    )
+    color_parser.add_argument("direction", choices=["next", "prev"], help="Which way to cycle")
+    color_parser.set_defaults(handler=do_color, writes=True)
+
+    tui_parser = subparsers.add_parser(
+        "tui",
+        help="Adjust brightness and color interactively",
+        description="Adjust brightness and pick a palette color in the terminal. Changes are applied as "
+        "you make them, and saved on exit (or reverted, if you leave with escape).",
+    )
+    tui_parser.set_defaults(handler=do_tui, writes=True)
+
+    profile_parser = subparsers.add_parser(
+        "profile",
+        help="Apply or list the profiles in the configuration",
+        description="Profiles are named brightness, color and/or zone color settings, in the configuration's "
+        "'profiles'.",
+    )
+    profile_subparsers = profile_parser.add_subparsers(dest="action", metavar="ACTION", required=True)
+    profile_apply_parser = profile_subparsers.add_parser(
+        "apply",
+        help="Apply a profile, and save it",
+        description="Write a profile's settings to sysfs and update the state file to match.",
+    )
+    profile_apply_parser.add_argument("name", help="The profile to apply")
+    profile_apply_parser.set_defaults(writes=True)
+    profile_list_parser = profile_subparsers.add_parser("list", help="List the configured profiles")
+    profile_list_parser.set_defaults(writes=False)
+    profile_parser.set_defaults(handler=do_profile)
+
+    snapshot_parser = subparsers.add_parser(
+        "snapshot",
+        help="Save and restore named states",
+        description="Snapshots are named states kept beside the state file, e.g. state.snapshot-evening.json, "
+        "apart from the state saved and restored across sleep.",
+    )
+    snapshot_subparsers = snapshot_parser.add_subparsers(dest="action", metavar="ACTION", required=True)
+    snapshot_save_parser = snapshot_subparsers.add_parser(
+        "save",
+        help="Save the current keyboard state as a snapshot",
+        description="Read brightness and color from sysfs and save them under a name, replacing any snapshot "
+        "of that name.",
+    )
+    snapshot_restore_parser = snapshot_subparsers.add_parser(
+        "restore",
+        help="Apply a snapshot",
+        description="Write a snapshot's brightness and color to sysfs, leaving the state file alone.",
+    )
+    snapshot_delete_parser = snapshot_subparsers.add_parser("delete", help="Remove a snapshot")
+    for named_parser in (snapshot_save_parser, snapshot_restore_parser, snapshot_delete_parser):
+        named_parser.add_argument("name", type=snapshot_name_argument, help="The snapshot's name")
+        named_parser.set_defaults(writes=True, scope="devices")
+    snapshot_list_parser = snapshot_subparsers.add_parser("list", help="List the snapshots")
+    snapshot_list_parser.set_defaults(writes=False)
+    snapshot_parser.set_defaults(handler=do_snapshot)
+
+    schedule_parser = subparsers.add_parser(
+        "schedule",
+        help="Manage time-of-day brightness and color changes",
+        description="Schedule entries are brightness and/or color settings for a time of day, kept in "
+        "schedule.json beside the state file. 'schedule apply', run from a timer, applies whichever entry "
+        "is due.",
+    )
+    schedule_subparsers = schedule_parser.add_subparsers(dest="action", metavar="ACTION", required=True)
+    schedule_add_parser = schedule_subparsers.add_parser(
+        "add",
+        help="Add an entry, replacing any at the same time",
+        description="Schedule brightness and/or color for a time of day.",
+    )
+    schedule_add_parser.add_argument("time", type=time_argument, help="Time of day, as HH:MM")
+    schedule_add_parser.add_argument(
+        "-b",
+        "--brightness",
+        type=brightness_argument,
+        help="Brightness, 0-255 or a percentage of the device's max_brightness (e.g. 50%%)",
+    )
+    schedule_add_parser.add_argument(
+        "-c",
+        "--color",
+        type=color_argument,
+        help="Color as RRGGBB hex, or a CSS color name",
+    )
+    schedule_add_parser.add_argument(
+        "-p",
+        "--profile",
+        metavar="NAME",
+        help="Apply this profile from the configuration (any --brightness or --color go on top of it)",
+    )
+    schedule_add_parser.set_defaults(writes=True)
+    schedule_remove_parser = schedule_subparsers.add_parser("remove", help="Remove the entry at a time")
+    schedule_remove_parser.add_argument("time", type=time_argument, help="Time of day, as HH:MM")
+    schedule_remove_parser.set_defaults(writes=True)
+    schedule_list_parser = schedule_subparsers.add_parser("list", help="List the scheduled entries")
+    schedule_list_parser.set_defaults(writes=False)
+    schedule_apply_parser = schedule_subparsers.add_parser(
+        "apply",
+        help="Apply the entry that is due now, and save it",
+        description="Apply the latest entry at or before the current time (or, before the first entry of the "
+        "day, the last one), and update the state file to match.",
+    )
+    schedule_apply_parser.set_defaults(writes=True)
+    schedule_parser.set_defaults(handler=do_schedule)
+
+    export_parser = subparsers.add_parser(
+        "export",
+        help="Print the saved state",
+        description="Print the saved state as JSON, in the form 'import' accepts, or as environment variables.",
+    )
+    export_parser.add_argument(
+        "--defaults",
+        action="store_true",
+        help="Also include the configured defaults (which import ignores)",
+    )
+    export_parser.add_argument(
+        "-f",
+        "--format",
+        choices=["json", "env"],
+        default="json",
+        help="Print JSON (the default), or S76_KBD_BRIGHTNESS=... lines for shell eval or a systemd EnvironmentFile=",
+    )
+    export_parser.set_defaults(handler=do_export, writes=False)
+
+    import_parser = subparsers.add_parser(
+        "import",
+        help="Replace the saved state with an exported one",
+        description="Read a state document, as printed by 'export', and save it as the current state.",
+    )
+    import_parser.add_argument("file", metavar="PATH", nargs="?", default="-", help="File to read (default: stdin)")
+    import_parser.add_argument("-a", "--apply", action="store_true", help="Also apply it to sysfs")
+    import_parser.set_defaults(handler=do_import, writes=True)
+
+    migrate_from_system76_power_parser = subparsers.add_parser(
+        "migrate-from-system76-power",
+        help="Save the keyboard settings system76-power kept as the state",
+        description="Read the keyboard brightness and color that system76-power persisted, from PATH or the "
+        f"keyboard and backlight files under {' and '.join(str(path) for path in SYSTEM76_POWER_PATHS)}, and "
+        "save them as the state, for switching over from it. Values not found keep what the keyboard shows now.",
+    )
+    migrate_from_system76_power_parser.add_argument("path", metavar="PATH", nargs="?", help="Settings file to read")
+    migrate_from_system76_power_parser.add_argument(
+        "-a", "--apply", action="store_true", help="Also apply it to sysfs"
+    )
+    migrate_from_system76_power_parser.set_defaults(handler=do_migrate_from_system76_power, writes=True)
+
+    identify_parser = subparsers.add_parser(
+        "identify",
+        help="Blink a device so it can be told apart",
+        description="Blink the keyboard backlight between off and full brightness a few times, then put back "
+        "the values it had. The state file is not touched.",
+    )
+    identify_parser.add_argument(
+        "-c",
+        "--count",
+        type=positive_int_argument,
+        default=3,
+        help="How many times to blink (default: 3)",
+    )
+    identify_parser.add_argument(
+        "-i",
+        "--interval",
+        type=float,
+        default=0.3,
+        help="Seconds between each change (default: 0.3)",
+    )
+    identify_parser.set_defaults(handler=do_identify, writes=True)
+
+    demo_parser = subparsers.add_parser(
+        "demo",
+        help="Run through the palette and a brightness ramp",
+        description="Show each palette color at full brightness, then ramp the brightness down and up, and "
+        "finally put back the values the keyboard had. The state file is not touched.",
+    )
+    demo_parser.add_argument(
+        "-i",
+        "--interval",
+        type=float,
+        default=0.5,
+        help="Seconds to hold each step (default: 0.5)",
+    )
+    demo_parser.set_defaults(handler=do_demo, writes=True)
+
+    calibrate_parser = subparsers.add_parser(
+        "calibrate",
+        help="Find which brightness levels look different, and save them",
+        description="Step through brightness values, asking at each one whether it looks brighter than the last "
+        "level kept, then store the distinct levels as 'brightness.levels' in the configuration file for "
+        "'brightness up/down' to step through. The keyboard is put back as it was afterwards.",
+    )
+    calibrate_parser.add_argument(
+        "-s",
+        "--steps",
+        type=positive_int_argument,
+        default=16,
+        help="How many values to try between off and max_brightness (default: 16)",
+    )
+    calibrate_parser.set_defaults(handler=do_calibrate, writes=True)
+
+    reset_parser = subparsers.add_parser(
+        "reset",
+        help="Apply and save the configured default brightness and color",
+        description="Write the configured defaults to sysfs and replace the state file with them.",
+    )
+    reset_parser.set_defaults(handler=do_reset, writes=True)
+
+    check_config_parser = subparsers.add_parser(
+        "check-config",
+        help="Validate the configuration",
+        description="Check that the configuration parses, its paths exist and are writable, "
+        "and its defaults are valid. Exits non-zero listing each problem found.",
+    )
+    check_config_parser.set_defaults(handler=do_check_config, writes=False, scope="configuration")
+
+    install_hooks_parser = subparsers.add_parser(
+        "install-hooks",
+        help="Install the systemd-sleep hook and service unit",
+        description=f"Install a hook in {SYSTEM_SLEEP_PATH} that runs this program on suspend and resume, "
+        f"and a service unit in {UNIT_PATH} that runs it at boot and shutdown. "
+        "Any --config or --state-file given is passed on by the hooks.",
+    )
+    install_hooks_parser.add_argument("--no-unit", action="store_true", help="Only install the sleep hook")
+    install_hooks_parser.add_argument(
+        "--force",
+        action="store_true",
+        help="Overwrite existing files even if they were not installed by this tool",
+    )
+    install_hooks_parser.set_defaults(handler=do_install_hooks, writes=True)
+
+    uninstall_hooks_parser = subparsers.add_parser(
+        "uninstall-hooks",
+        help="Remove the hooks installed by install-hooks",
+        description="Remove the sleep hook and service unit installed by install-hooks. "
+        "Files at those locations that were not installed by this tool are left alone.",
+    )
+    uninstall_hooks_parser.set_defaults(handler=do_uninstall_hooks, writes=True)
+
+    for hooks_parser in (install_hooks_parser, uninstall_hooks_parser):
+        hooks_parser.add_argument(
+            "--hook-dir",
+            metavar="PATH",
+            default=str(SYSTEM_SLEEP_PATH),
+            help=f"Directory of the sleep hook (default: {SYSTEM_SLEEP_PATH})",
+        )
+        hooks_parser.add_argument(
+            "--unit-dir",
+            metavar="PATH",
+            default=str(UNIT_PATH),
+            help=f"Directory of the service unit (default: {UNIT_PATH})",
+        )
+
+    watch_parser = subparsers.add_parser(
+        "watch",
+        help="Print brightness and color changes as they happen",
+        description="Poll the sysfs attributes and print each change with a timestamp, until interrupted. "
+        "With --json, each change is printed as a JSON line. SIGHUP reloads the configuration.",
+    )
+    watch_parser.add_argument(
+        "-i",
+        "--interval",
+        type=float,
+        default=0.5,
+        help="Seconds between polls (default: 0.5)",
+    )
+    watch_parser.set_defaults(handler=do_watch, writes=False)
+
+    track_parser = subparsers.add_parser(
+        "track",
+        help="Save brightness and color changes as they happen",
+        description="Poll the sysfs attributes until interrupted, saving the state whenever it changes and then "
+        "stays unchanged for --settle seconds, e.g. after the Fn keys are used. Changes right after a resume are "
+        "left to 'restore'. SIGHUP reloads the configuration.",
+    )
+    track_parser.add_argument(
+        "-i",
+        "--interval",
+        type=float,
+        default=0.5,
+        help="Seconds between polls (default: 0.5)",
+    )
+    track_parser.add_argument(
+        "--settle",
+        type=float,
+        default=2,
+        help="Seconds a change has to last before it is saved (default: 2)",
+    )
+    track_parser.set_defaults(handler=do_track, writes=True)
+
+    init_config_parser = subparsers.add_parser(
+        "init-config",
+        help="Write a commented configuration for this machine",
+        description="Write a TOML configuration, with every setting explained, holding the built-in defaults "
+        "and the keyboard backlight devices found on this machine.",
+    )
+    init_config_parser.add_argument("path", metavar="PATH", help="Where to write it, or - for stdout")
+    init_config_parser.add_argument("-f", "--force", action="store_true", help="Replace PATH if it exists")
+    init_config_parser.set_defaults(handler=do_init_config, writes=False, scope="configuration")
+
+    migrate_config_parser = subparsers.add_parser(
+        "migrate-config",
+        help="Convert the configuration to TOML",
+        description="Convert the configuration in use (or the one given with --config) to TOML, with the "
+        "settings of a single device moved under 'devices', and any '//', '_comment' or 'comment' keys "
+        "kept as comments.",
+    )
+    migrate_config_parser.add_argument(
+        "path",
+        metavar="PATH",
+        nargs="?",
+        help="Where to write it, or - for stdout (default: beside the configuration, with a .toml suffix)",
+    )
+    migrate_config_parser.add_argument("-f", "--force", action="store_true", help="Replace PATH if it exists")
+    migrate_config_parser.set_defaults(handler=do_migrate_config, writes=False, scope="configuration")
+
+    list_devices_parser = subparsers.add_parser(
+        "list-devices",
+        help="List keyboard backlight LED devices",
+        description="Scan for *kbd_backlight* LED class devices and show their attributes and max_brightness, "
+        "for use when writing the configuration.",
+    )
+    list_devices_parser.add_argument(
+        "--path",
+        metavar="PATH",
+        default=str(LEDS_PATH),
+        help=f"Directory to scan (default: {LEDS_PATH})",
+    )
+    list_devices_parser.set_defaults(handler=do_list_devices, writes=False)
+
+    doctor_parser = subparsers.add_parser(
+        "doctor",
+        help="Diagnose common setup problems",
+        description="Check for the System76 kernel modules, list candidate LED devices, and verify access "
+        "to the configured sysfs attributes and state directory. Exits non-zero if anything failed.",
+    )
+    doctor_parser.set_defaults(handler=do_doctor, writes=False, scope="configuration")
+
+    status_parser = subparsers.add_parser(
+        "status",
+        help="Show hardware, saved and default state",
+        description="Print the current sysfs values next to the saved state and configured defaults.",
+    )
+    status_parser.set_defaults(handler=do_status, writes=False)
+
+    diff_parser = subparsers.add_parser(
+        "diff",
+        help="Compare the saved state with the hardware",
+        description="Print how the keyboard differs from the state 'restore' would apply, exiting with status 1 if "
+        "it does, e.g. for scripts deciding whether to restore.",
+    )
+    diff_parser.set_defaults(handler=do_diff, writes=False)
+
+    for device_parser in (
+        save_parser, restore_parser, set_parser, history_parser, undo_parser, toggle_parser, brightness_parser,
+        color_parser, tui_parser, profile_parser, snapshot_parser, schedule_parser, export_parser, import_parser,
+        migrate_from_system76_power_parser, identify_parser, demo_parser, calibrate_parser, reset_parser,
+        watch_parser, track_parser, status_parser, diff_parser,
+    ):
+        device_parser.add_argument(
+            "-d",
+            "--device",
+            metavar="NAME",
+            help="Act on the configured device NAME, as shown by 'status', rather than the first one "
+            "(save and restore otherwise act on every device)",
+        )
+    # and after the action, as every other command takes it at the end; suppressed when left out, so as not to
+    # replace one given before the action
+    for action_parser in (
+        history_list_parser, profile_apply_parser, profile_list_parser, snapshot_save_parser, snapshot_restore_parser,
+        snapshot_delete_parser, snapshot_list_parser, schedule_add_parser, schedule_remove_parser,
+        schedule_list_parser, schedule_apply_parser,
+    ):
+        action_parser.add_argument(
+            "-d",
+            "--device",
+            metavar="NAME",
+            default=argparse.SUPPRESS,
+            help="Act on the configured device NAME, as shown by 'status', rather than the first one",
+        )
+
+    completions_parser = subparsers.add_parser(
+        "completions",
+        help="Print a shell completion script",
+        description="Print a completion script for the given shell, generated from this program's arguments.",
+    )
+    completions_parser.add_argument("shell", choices=["bash", "zsh", "fish"], help="The shell to generate it for")
+    completions_parser.set_defaults(handler=do_completions, writes=False)
+
+    version_parser = subparsers.add_parser(
+        "version",
+        help="Show version information",
+        description="Show the version; with --verbose, also the details a bug report should include.",
+    )
+    version_parser.add_argument(
+        "-v",
+        "--verbose",
+        dest="verbose_version",
+        action="store_true",
+        help="Include platform, git commit, optional features and built-in paths",
+    )
+    version_parser.set_defaults(handler=do_version, writes=False)
+
+    # deliberately given no help, so that it stays out of the command listing
+    generate_man_parser = subparsers.add_parser(
+        "generate-man",
+        description="Print a roff man page generated from this program's arguments.",
+    )
+    generate_man_parser.set_defaults(handler=do_generate_man, writes=False)
+    return parser
+
+
+def configuration_for(args: argparse.Namespace) -> dict:
+    # check-config and doctor make their own, more thorough, report of any problems
+    configuration = read_configuration(
+        args.config,
+        lenient=args.lenient,
+        validate=args.handler not in (do_check_config, do_doctor),
+    )
+    if os.geteuid() != 0 and args.state_file is None and "S76_KBD_STATE_PATH" not in os.environ:
+        # unprivileged runs keep their own state, e.g. for restoring on login, apart from the system-wide one
+        user_state_path = configuration.get("user_state_path", DEFAULT_USER_STATE_PATH)
+        if type(user_state_path) is str:
+            configuration["state_path"] = expand_path(user_state_path)
+    if args.state_file is not None:
+        configuration["state_path"] = args.state_file
+    if args.backend is not None:
+        # for every device, e.g. '--backend mock' to try things out without the hardware
+        configuration["backend"] = args.backend
+        for device in device_tables(configuration).values():
+            device["backend"] = args.backend
+    state_path = configuration["state_path"]
+    if getattr(args, "slot", None) is not None:
+        configuration["state_path"] = slot_state_path(configuration["state_path"], args.slot)
+    if getattr(args, "history", None) is not None:
+        configuration["state_path"] = history_state_path(configuration["state_path"], args.history)
+    if os.geteuid() != 0 or args.state_file is not None or configuration["state_path"] != state_path:
+        # falling back is for the system-wide state; a state file chosen some other way is used as it is
+        configuration["runtime_state_path"] = None
+    return configuration
+
+
+def user_may_write(configuration: dict, args: argparse.Namespace) -> bool:
+    # commands that only touch the LED devices and the state can run unprivileged where udev rules (or the like)
+    # have made the attributes writable, with the state kept per user
+    if getattr(args, "scope", "device") == "configuration" or args.handler in (do_install_hooks, do_uninstall_hooks):
+        return False
+    return all(device_backend(device).writable(device) for device in configured_devices(configuration).values())
+
+
+def run_handler(configuration: dict, args: argparse.Namespace):
+    scope = getattr(args, "scope", "device")
+    if scope == "configuration":
+        return args.handler(configuration, args)
+    devices = configured_devices(configuration)
+    name = getattr(args, "device", None)
+    if name is not None or scope == "device" or len(devices) == 1:
+        return args.handler(select_device(configuration, name), args)
+    # save and restore, as run by the sleep hook, cover every device
+    return {name: args.handler(device, args) for name, device in devices.items()}
+
+
+def main():
+    parser = build_parser()
+    args = parser.parse_args()
+    if args.quiet:
+        log_level = logging.ERROR
+    else:
+        log_level = [logging.WARNING, logging.INFO, logging.DEBUG][min(args.verbose, 2)]
+    logging.basicConfig(format="%(levelname)s: %(message)s", level=log_level)
+    started = time.monotonic()
+    if args.handler is do_post and args.slot is not None and args.history is not None:
+        parser.error("restore: --slot and --from cannot be used together")
+    if args.handler is do_set and (args.brightness, args.color, args.zone, args.stdin) == (None, None, None, False):
+        parser.error("set: at least one of --brightness, --color, --zone or --stdin is required")
+    if args.handler is do_schedule and args.action == "add" and (args.brightness, args.color, args.profile) == (
+        None,
+        None,
+        None,
+    ):
+        parser.error("schedule add: at least one of --brightness, --color or --profile is required")
+    try:
+        configuration = configuration_for(args)
+        if args.writes and not args.dry_run and os.geteuid() != 0 and not user_may_write(configuration, args):
+            raise PermissionDeniedError(f"'{args.command}' must be run as root, or with --dry-run to preview it")
+        if args.json:
+            # keep stdout clean for the JSON document
+            with contextlib.redirect_stdout(sys.stderr):
+                result = run_handler(configuration, args)
+            print(json.dumps({"command": args.command, "dry_run": args.dry_run, "result": result}))
+        else:
+            run_handler(configuration, args)
+    except Exception as e:
+        exit_code = e.exit_code if isinstance(e, StatemgrError) else EXIT_FAILURE
+        problems = getattr(e, "problems", None)
+        if not args.json:
+            if not isinstance(e, StatemgrError):
+                raise
+            if problems is not None and not e.reported:
+                for problem in problems:
+                    print(f"problem: {problem}", file=sys.stderr)
+            logger.error(str(e))
+            sys.exit(exit_code)
+        error = {"type": type(e).__name__, "message": str(e), "exit_code": exit_code}
+        if problems is not None:
+            error["problems"] = problems
+        print(json.dumps({"command": args.command, "error": error}))
+        sys.exit(exit_code)
+    logger.debug(f"'{args.command}' completed in {(time.monotonic() - started) * 1000:.1f} ms")
 
 
 if __name__ == "__main__":